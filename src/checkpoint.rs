@@ -0,0 +1,160 @@
+use crate::metadata::Metadata;
+use crate::snapshot::{
+    invalid, read_flag, read_len, read_metadata, read_varint, write_metadata, write_varint,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Write};
+
+// A checkpoint file records the state of a durable database after the first `log_len` bytes of
+// its write-ahead log were applied, so that opening the database only replays the changes logged
+// after it. The reverse index is not part of it: it is in the segment files, which are flushed
+// before the checkpoint is written. The texts are not part of it either: they are in the content
+// file, which is never overwritten, so the checkpoint only records where each text starts. The
+// layout is
+//
+//     magic: b"NGRAMCKP"  version: u32
+//     the fingerprint of the configuration, the length of the log and of the content file
+//     document count, then for each document:
+//         0 if it was deleted, or 1 followed by the offset and length of its text in the content
+//         file, its indexed word count and its content hash
+//     metadata count, then for each document with metadata, in order of id:
+//         the document id and the metadata
+//     JSON document count, then the id of each document published as JSON, in increasing order
+//         and delta encoded
+//
+// with every number a varint, and metadata as in a snapshot file.
+
+const MAGIC: &[u8; 8] = b"NGRAMCKP";
+/// The version of the checkpoint format written by `Checkpoint::write_to`
+pub const VERSION: u32 = 1;
+
+/// The state of a durable database at a point in its write-ahead log
+pub struct Checkpoint {
+    /// A hash of the options that decide what is indexed. A checkpoint taken with other options
+    /// does not match the segments those options would have written.
+    pub fingerprint: u64,
+    /// The length of the log the checkpoint was taken after
+    pub log_len: u64,
+    /// The length of the content file when the checkpoint was taken
+    pub content_len: u64,
+    /// Each document, indexed by document id. Deleted documents are `None`.
+    pub documents: Vec<Option<CheckpointDocument>>,
+    /// The metadata of the documents that have any, by document id
+    pub metadata: BTreeMap<usize, Metadata>,
+    /// The ids of the documents that were published as JSON
+    pub json: BTreeSet<usize>,
+}
+
+/// A document as recorded in a checkpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckpointDocument {
+    /// The offset of its text in the content file
+    pub offset: u64,
+    /// The length of its text in bytes
+    pub len: usize,
+    /// The number of indexed words in it
+    pub length: usize,
+    /// The hash of its text, used to find duplicates
+    pub hash: u64,
+}
+
+impl Checkpoint {
+    /// Write the checkpoint to `writer`
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        write_varint(&mut writer, self.fingerprint)?;
+        write_varint(&mut writer, self.log_len)?;
+        write_varint(&mut writer, self.content_len)?;
+
+        write_varint(&mut writer, self.documents.len() as u64)?;
+        for document in self.documents.iter() {
+            match document {
+                Some(document) => {
+                    writer.write_all(&[1])?;
+                    write_varint(&mut writer, document.offset)?;
+                    write_varint(&mut writer, document.len as u64)?;
+                    write_varint(&mut writer, document.length as u64)?;
+                    write_varint(&mut writer, document.hash)?;
+                }
+                None => writer.write_all(&[0])?,
+            }
+        }
+
+        write_varint(&mut writer, self.metadata.len() as u64)?;
+        for (&id, metadata) in self.metadata.iter() {
+            write_varint(&mut writer, id as u64)?;
+            write_metadata(&mut writer, metadata)?;
+        }
+
+        write_varint(&mut writer, self.json.len() as u64)?;
+        let mut previous = 0;
+        for &id in self.json.iter() {
+            write_varint(&mut writer, (id - previous) as u64)?;
+            previous = id;
+        }
+
+        writer.flush()
+    }
+
+    /// Read a checkpoint written by `write_to` from `reader`
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Checkpoint> {
+        let mut header = [0; 12];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC || header[8..] != VERSION.to_be_bytes() {
+            return Err(invalid("not a checkpoint file"));
+        }
+        let fingerprint = read_varint(&mut reader)?;
+        let log_len = read_varint(&mut reader)?;
+        let content_len = read_varint(&mut reader)?;
+
+        let mut documents = Vec::new();
+        for _ in 0..read_len(&mut reader)? {
+            if !read_flag(&mut reader)? {
+                documents.push(None);
+                continue;
+            }
+            let document = CheckpointDocument {
+                offset: read_varint(&mut reader)?,
+                len: read_len(&mut reader)?,
+                length: read_len(&mut reader)?,
+                hash: read_varint(&mut reader)?,
+            };
+            let end = document.offset.checked_add(document.len as u64);
+            if end.is_none_or(|end| end > content_len) {
+                return Err(invalid("document past the end of the content file"));
+            }
+            documents.push(Some(document));
+        }
+
+        let mut metadata = BTreeMap::new();
+        for _ in 0..read_len(&mut reader)? {
+            let id = read_len(&mut reader)?;
+            if id >= documents.len() {
+                return Err(invalid("metadata for an unknown document"));
+            }
+            metadata.insert(id, read_metadata(&mut reader)?);
+        }
+
+        let mut json = BTreeSet::new();
+        let mut id: usize = 0;
+        for _ in 0..read_len(&mut reader)? {
+            id = id
+                .checked_add(read_len(&mut reader)?)
+                .ok_or_else(|| invalid("document id is too large"))?;
+            if !matches!(documents.get(id), Some(Some(_))) {
+                return Err(invalid("JSON flag for an unknown document"));
+            }
+            json.insert(id);
+        }
+
+        Ok(Checkpoint {
+            fingerprint,
+            log_len,
+            content_len,
+            documents,
+            metadata,
+            json,
+        })
+    }
+}
@@ -1,4 +1,5 @@
 use crate::message::*;
+use crate::metadata::Metadata;
 use std::default::Default;
 use std::io::Write;
 use std::net::{SocketAddr, TcpStream};
@@ -72,20 +73,144 @@ impl Client {
 
     }
     // TODO:
-    // Send a `Search` request to the server with the given `word`. Return the response from the
+    // Send a `Search` request to the server with the given `query`. Return the response from the
     // server.
-    pub fn search(&self, word: &str) -> Option<Response> {
+    pub fn search(&self, query: &str) -> Option<Response> {
         
-        let request = Request::Search { word : word.to_string() };
+        self.search_with_options(query, SearchOptions::default())
+
+    }
+
+    /// Send a `Search` request to the server with the given `query` and `options`, such as
+    /// asking for snippets of the matching documents. Return the response from the server.
+    pub fn search_with_options(&self, query: &str, options: SearchOptions) -> Option<Response> {
+        let request = Request::Search {
+            word: query.to_string(),
+            options,
+        };
 
         self.send(&request)
+    }
+
+    /// Send a `RankedSearch` request to the server with the given `query`. Return the response
+    /// from the server.
+    pub fn ranked_search(&self, query: &str) -> Option<Response> {
+        let request = Request::RankedSearch {
+            query: query.to_string(),
+        };
 
+        self.send(&request)
     }
     // TODO:
     // Send a `Retrieve` request to the server with the given `id`. Return the response from the
     // server.
     pub fn retrieve(&self, id: usize) -> Option<Response> {
-        let request = Request::Retrieve { id: id };
+        let request = Request::Retrieve { id };
+
+        self.send(&request)
+    }
+
+    /// Send a `FuzzySearch` request to the server for words similar to `word` with at least the
+    /// given `threshold` of similarity. Return the response from the server.
+    pub fn fuzzy_search(&self, word: &str, threshold: f64) -> Option<Response> {
+        let request = Request::FuzzySearch {
+            word: word.to_string(),
+            threshold,
+        };
+
+        self.send(&request)
+    }
+
+    /// Send a `Delete` request to the server with the given `id`. Return the response from the
+    /// server.
+    pub fn delete(&self, id: usize) -> Option<Response> {
+        let request = Request::Delete { id };
+
+        self.send(&request)
+    }
+
+    /// Read the file at `path` and send an `Update` request to the server that replaces the
+    /// document with the given `id` by its contents. Return the response from the server.
+    pub fn update_from_path(&self, id: usize, path: &str) -> Option<Response> {
+        let doc = std::fs::read_to_string(path).unwrap();
+
+        let request = Request::Update { id, doc };
+
+        self.send(&request)
+    }
+
+    /// Send a `Snapshot` request asking the server to write a snapshot of its archive to `path`
+    /// inside the server's snapshot directory. Return the response from the server.
+    pub fn snapshot(&self, path: &str) -> Option<Response> {
+        let request = Request::Snapshot {
+            path: path.to_string(),
+        };
+
+        self.send(&request)
+    }
+
+    /// Send an `IndexStats` request for the size of the server's reverse index. Return the
+    /// response from the server.
+    pub fn index_stats(&self) -> Option<Response> {
+        self.send(&Request::IndexStats)
+    }
+
+    /// Read the file at `path` and send a `PublishWithMetadata` request to the server with its
+    /// contents and `metadata`. Return the response from the server.
+    pub fn publish_with_metadata_from_path(
+        &self,
+        path: &str,
+        metadata: Metadata,
+    ) -> Option<Response> {
+        let doc = std::fs::read_to_string(path).unwrap();
+
+        let request = Request::PublishWithMetadata { doc, metadata };
+
+        self.send(&request)
+    }
+
+    /// Send a `RetrieveMetadata` request to the server for the metadata of the document with the
+    /// given `id`. Return the response from the server.
+    pub fn retrieve_metadata(&self, id: usize) -> Option<Response> {
+        let request = Request::RetrieveMetadata { id };
+
+        self.send(&request)
+    }
+
+    /// Send a `SearchRegex` request to the server for documents containing a word that matches
+    /// the regular expression `pattern`. Return the response from the server.
+    pub fn search_regex(&self, pattern: &str) -> Option<Response> {
+        let request = Request::SearchRegex {
+            pattern: pattern.to_string(),
+        };
+
+        self.send(&request)
+    }
+
+    /// Read the JSON object in the file at `path` and send a `PublishJson` request to the server
+    /// with its contents. Return the response from the server.
+    pub fn publish_json_from_path(&self, path: &str) -> Option<Response> {
+        let doc = std::fs::read_to_string(path).unwrap();
+
+        let request = Request::PublishJson { doc };
+
+        self.send(&request)
+    }
+
+    /// Send an `NgramStats` request to the server for the number of occurrences of the phrase
+    /// `ngram` in every document. Return the response from the server.
+    pub fn ngram_stats(&self, ngram: &str) -> Option<Response> {
+        let request = Request::NgramStats {
+            ngram: ngram.to_string(),
+        };
+
+        self.send(&request)
+    }
+
+    /// Send a `TopNgrams` request to the server for the `k` most frequent n-grams of `n` words.
+    /// Return the response from the server.
+    pub fn top_ngrams(&self, n: usize, k: usize) -> Option<Response> {
+        let request = Request::TopNgrams { n, k };
 
         self.send(&request)
     }
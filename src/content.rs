@@ -0,0 +1,282 @@
+use crate::segment::read_exact_at;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock};
+
+// Documents are appended to a content file and never moved or overwritten. An offset table in
+// memory maps each document id to the extent of its text in the file, so a retrieve only holds
+// the table's read lock long enough to look up the extent, and then reads the text with a
+// positioned read that runs in parallel with other retrieves and with appends. Updating a
+// document appends its new text and points its entry at it; deleting clears the entry. The old
+// text is left behind in the file.
+//
+// Writing text and pointing an entry at it are separate steps, so that a caller can record a
+// change elsewhere after the only step that can fail and before the change becomes visible. Text
+// that is written but never pointed at is left behind like replaced text.
+//
+// A durable store is reopened from the layout recorded when its database was checkpointed. Text
+// written after the checkpoint is cut off, since the changes that wrote it are replayed from the
+// log and write it again.
+//
+// A store that need not outlive the process uses an unlinked temporary file. Only when no
+// temporary file can be created does it keep the same layout in a buffer in memory instead, which
+// holds every text on the heap and copies them all whenever the buffer grows.
+
+/// The number of temporary content files created by this process, used to name the next one
+static TEMPORARY_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Where the text of a document is stored in the content file
+#[derive(Clone, Copy, Debug)]
+struct Extent {
+    offset: u64,
+    len: usize,
+}
+
+/// Text written to the end of the content file by `ContentEditor::write` that no document points
+/// at yet
+#[derive(Debug)]
+pub struct Staged(Extent);
+
+/// An append-only store of document texts, indexed by document id
+pub struct ContentStore {
+    /// Where the texts are kept
+    backing: Backing,
+    /// Held while the store is changed, with the number of bytes of text written so far
+    writer: Mutex<u64>,
+    /// The extent of each document in the file. Deleted documents are `None` so that ids stay
+    /// stable.
+    extents: RwLock<Vec<Option<Extent>>>,
+}
+
+enum Backing {
+    /// A content file on disk
+    File {
+        path: PathBuf,
+        /// Whether the file belongs to this store alone and is removed when it is dropped
+        temporary: bool,
+        /// A read-only handle used for positioned reads
+        reader: File,
+        /// The handle appends go through
+        writer: File,
+    },
+    /// The texts one after another in memory, for stores that need not outlive the process
+    Memory(RwLock<Vec<u8>>),
+}
+
+impl ContentStore {
+    /// Create an empty store in the file at `path`, replacing any file already there
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Self::with_file(path.to_path_buf(), false)
+    }
+
+    /// Open the store in the file at `path` as it was when `layout` returned `len` and
+    /// `extents`, cutting off any text written since. Fails if the file is shorter than `len`.
+    pub fn open(path: &Path, len: u64, extents: Vec<Option<(u64, usize)>>) -> io::Result<Self> {
+        let mut writer = OpenOptions::new().write(true).open(path)?;
+        if writer.metadata()?.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is shorter than its checkpoint", path.display()),
+            ));
+        }
+        writer.set_len(len)?;
+        writer.seek(SeekFrom::Start(len))?;
+        let reader = File::open(path)?;
+
+        let store = Self::with_backing(Backing::File {
+            path: path.to_path_buf(),
+            temporary: false,
+            reader,
+            writer,
+        });
+        *store.writer.lock().unwrap() = len;
+        *store.extents.write().unwrap() = extents
+            .into_iter()
+            .map(|extent| extent.map(|(offset, len)| Extent { offset, len }))
+            .collect();
+        Ok(store)
+    }
+
+    /// Create an empty store in a new file in the system's temporary directory, which is
+    /// removed again when the store is dropped
+    pub fn temporary() -> io::Result<Self> {
+        let number = TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed);
+        let name = format!("ngram-content-{}-{}.dat", std::process::id(), number);
+        let mut store = Self::with_file(std::env::temp_dir().join(name), true)?;
+
+        // Open files can be unlinked on Unix, which guarantees the file is cleaned up even if
+        // the process exits without dropping the store
+        if let Backing::File {
+            path, temporary, ..
+        } = &mut store.backing
+        {
+            if cfg!(unix) {
+                fs::remove_file(path)?;
+                *temporary = false;
+            }
+        }
+        Ok(store)
+    }
+
+    /// Create an empty store that keeps the texts in memory, for when no temporary file can be
+    /// created. Unlike a store backed by a file, it can never fail to write.
+    pub fn memory() -> Self {
+        Self::with_backing(Backing::Memory(RwLock::new(Vec::new())))
+    }
+
+    fn with_file(path: PathBuf, temporary: bool) -> io::Result<Self> {
+        let writer = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let reader = File::open(&path)?;
+
+        Ok(Self::with_backing(Backing::File {
+            path,
+            temporary,
+            reader,
+            writer,
+        }))
+    }
+
+    fn with_backing(backing: Backing) -> Self {
+        ContentStore {
+            backing,
+            writer: Mutex::new(0),
+            extents: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Read the text of the document with the given id, or `None` if there is no such document
+    pub fn get(&self, id: usize) -> io::Result<Option<String>> {
+        let Some(Some(extent)) = self.extents.read().unwrap().get(id).copied() else {
+            return Ok(None);
+        };
+        self.read(extent).map(Some)
+    }
+
+    /// The number of bytes of text written so far, and the offset and length of the text of
+    /// each document, indexed by document id, with `None` for deleted documents
+    pub fn layout(&self) -> (u64, Vec<Option<(u64, usize)>>) {
+        let end = self.writer.lock().unwrap();
+        let extents = self.extents.read().unwrap();
+        let extents = extents
+            .iter()
+            .map(|extent| extent.map(|extent| (extent.offset, extent.len)))
+            .collect();
+        (*end, extents)
+    }
+
+    /// Flush the texts written so far to disk
+    pub fn sync(&self) -> io::Result<()> {
+        match &self.backing {
+            Backing::File { writer, .. } => writer.sync_data(),
+            Backing::Memory(_) => Ok(()),
+        }
+    }
+
+    /// Lock the store for changes. Changes are applied in the order their callers lock the
+    /// store, which never blocks `get`.
+    pub fn edit(&self) -> ContentEditor<'_> {
+        ContentEditor {
+            store: self,
+            end: self.writer.lock().unwrap(),
+        }
+    }
+
+    fn read(&self, extent: Extent) -> io::Result<String> {
+        let mut bytes = vec![0; extent.len];
+        match &self.backing {
+            Backing::File { reader, .. } => read_exact_at(reader, &mut bytes, extent.offset)?,
+            Backing::Memory(buffer) => {
+                let start = extent.offset as usize;
+                bytes.copy_from_slice(&buffer.read().unwrap()[start..start + extent.len]);
+            }
+        }
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl Drop for ContentStore {
+    fn drop(&mut self) {
+        if let Backing::File {
+            path,
+            temporary: true,
+            ..
+        } = &self.backing
+        {
+            if let Err(e) = fs::remove_file(path) {
+                eprintln!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Exclusive access to change a `ContentStore`, obtained from `ContentStore::edit`
+pub struct ContentEditor<'a> {
+    store: &'a ContentStore,
+    /// The number of bytes of text written so far
+    end: MutexGuard<'a, u64>,
+}
+
+impl ContentEditor<'_> {
+    /// Append `text` as a new document and return its id
+    pub fn push(&mut self, text: &str) -> io::Result<usize> {
+        let staged = self.write(text)?;
+        Ok(self.insert(staged))
+    }
+
+    /// Write `text` to the end of the file without making it the text of any document yet
+    pub fn write(&mut self, text: &str) -> io::Result<Staged> {
+        self.append(text).map(Staged)
+    }
+
+    /// Add the written text `staged` as a new document and return its id
+    pub fn insert(&mut self, staged: Staged) -> usize {
+        let mut extents = self.store.extents.write().unwrap();
+        extents.push(Some(staged.0));
+        extents.len() - 1
+    }
+
+    /// Make the written text `staged` the text of the document with the given id, or delete the
+    /// document if `staged` is `None`. Ids that were never pushed are ignored.
+    pub fn set(&mut self, id: usize, staged: Option<Staged>) {
+        if let Some(extent) = self.store.extents.write().unwrap().get_mut(id) {
+            *extent = staged.map(|staged| staged.0);
+        }
+    }
+
+    /// Reserve an id for a document that has already been deleted, and return it
+    pub fn push_deleted(&mut self) -> usize {
+        let mut extents = self.store.extents.write().unwrap();
+        extents.push(None);
+        extents.len() - 1
+    }
+
+    // Write `text` to the end of the file, before any entry points at it.
+    fn append(&mut self, text: &str) -> io::Result<Extent> {
+        let end = *self.end;
+        match &self.store.backing {
+            Backing::File { writer, .. } => {
+                let mut file = writer;
+                if let Err(e) = file.write_all(text.as_bytes()) {
+                    // Cut off whatever part of the text made it, so the next append starts at
+                    // `end`
+                    file.set_len(end)?;
+                    file.seek(SeekFrom::Start(end))?;
+                    return Err(e);
+                }
+            }
+            Backing::Memory(buffer) => buffer.write().unwrap().extend_from_slice(text.as_bytes()),
+        }
+
+        *self.end += text.len() as u64;
+        Ok(Extent {
+            offset: end,
+            len: text.len(),
+        })
+    }
+}
@@ -0,0 +1,15 @@
+// The occurrences of an n-gram are counted from its posting lists when they are asked for, and
+// the most frequent n-grams are found by visiting every key of the reverse index, so no count is
+// kept alongside the index that would have to hold a second copy of every n-gram.
+
+/// How often an n-gram occurs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NgramStats {
+    /// The number of occurrences across all documents
+    pub total: usize,
+    /// The number of documents it occurs in
+    pub documents: usize,
+    /// The id of every document it occurs in, in increasing order, with the number of
+    /// occurrences in that document
+    pub counts: Vec<(usize, usize)>,
+}
@@ -1,30 +1,508 @@
+use crate::checkpoint::{Checkpoint, CheckpointDocument};
+use crate::content::ContentStore;
+use crate::counts::NgramStats;
+use crate::index::{IndexStats, InvertedIndex};
+use crate::json::JsonDocument;
+use crate::message::Request;
+use crate::metadata::Metadata;
 use crate::multimap::ConcurrentMultiMap;
-use std::sync::Mutex;
+use crate::query::{Query, QueryError};
+use crate::snapshot::Snapshot;
+use crate::snippet::{SearchHit, Snippet};
+use crate::terms::TermDictionary;
+use crate::tokenizer::{DefaultTokenizer, Tokenizer};
+use crate::wal::WriteAheadLog;
+use regex::RegexBuilder;
+use std::collections::hash_map::DefaultHasher;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
-// The archive struct contains two data structures: a ConcurrentMultiMap for storing the
-// reverse index that maps words to the documents they appear in, and a Mutex<Vec<String>> for
-// storing the documents themselves. Since the documents themselves aren't accessed as often, it's
-// ok to keep them behind a single mutex.
+/// The number of snapshots started so far, used to give each its own temporary file
+static TEMPORARY_SNAPSHOTS: AtomicUsize = AtomicUsize::new(0);
+
+// The archive struct contains two data structures: an InvertedIndex for storing the reverse
+// index that maps words to the documents they appear in, and a ContentStore for storing the
+// documents themselves. Documents live in an append-only file, so retrieving one never waits for
+// publishes and large documents stay off the heap. The file of an in-memory archive is an
+// unlinked temporary file, which goes away with the process.
 
 /// A document database that allows clients to publish documents and
-/// search for documents containing specific words.
+/// search for documents containing specific words or phrases.
 pub struct Database {
-    /// A map from words to the set of documents that contain them
-    reverse_index: ConcurrentMultiMap<String, usize>,
-    /// A store of all documents in the database
-    blob_store: Mutex<Vec<String>>,
+    /// A map from word n-grams to the postings of the documents that contain them
+    reverse_index: InvertedIndex,
+    /// A map from character trigrams to the indexed words that contain them
+    trigram_index: ConcurrentMultiMap<String, String>,
+    /// Every indexed word in sorted order, used to expand wildcard patterns
+    terms: TermDictionary,
+    /// A store of all documents in the database, kept in the data directory if the database is
+    /// durable and in a temporary file otherwise. Deleted documents keep their ids.
+    blob_store: ContentStore,
+    /// The number of indexed words in each document, indexed by document id. Deleted documents
+    /// are `None`.
+    doc_lengths: RwLock<Vec<Option<usize>>>,
+    /// The metadata of the documents that were published with any
+    metadata: RwLock<HashMap<usize, Metadata>>,
+    /// The ids of the documents that were published as JSON, which are indexed by their string
+    /// values rather than by their text
+    json_documents: RwLock<HashSet<usize>>,
+    /// The normalized stop words, which are left out of the index
+    stop_words: HashSet<String>,
+    /// The ids of the documents with each content hash, used to find duplicates
+    content_hashes: Mutex<HashMap<u64, Vec<usize>>>,
+    /// Held for reading while a document is published, from making its id visible until it is
+    /// fully indexed, and for writing by deletions and updates. These remove the postings of an
+    /// existing document, so they must neither interleave with each other nor run before the
+    /// postings of a newly published document have all been added.
+    edit_lock: RwLock<()>,
+    /// Held for reading for the whole of every change to the archive, and for writing while a
+    /// snapshot is taken so that the snapshot never sees a half-applied change
+    changes: RwLock<()>,
+    /// The log that changes are recorded in, if the database is durable
+    wal: Option<Mutex<WriteAheadLog>>,
+    /// The directory a durable database keeps its files in
+    data_dir: Option<PathBuf>,
+    /// The number of changes logged since the last checkpoint
+    logged: AtomicUsize,
+    /// The options the database was created with
+    config: DatabaseConfig,
+}
+
+/// Options controlling how a `Database` indexes the documents published to it
+#[derive(Clone)]
+pub struct DatabaseConfig {
+    /// The largest n-gram size to index. Every run of 1 to `max_ngram` consecutive words is
+    /// stored in the reverse index as its own key, so phrases of up to that many words are found
+    /// with a single lookup. Values below 1 are treated as 1.
+    pub max_ngram: usize,
+    /// The tokenizer that splits both documents and queries into words
+    pub tokenizer: Arc<dyn Tokenizer>,
+    /// Words too common to be worth indexing. They are normalized with `tokenizer`, skipped when
+    /// publishing, and cannot be searched for on their own.
+    pub stop_words: HashSet<String>,
+    /// The number of postings a durable database keeps in memory before writing them to an
+    /// immutable segment file in its data directory. In-memory databases ignore it.
+    pub flush_threshold: usize,
+    /// Whether publishing a document with the same content as one already in the database
+    /// returns the existing document's id instead of adding a copy
+    pub deduplicate: bool,
+    /// The largest number of words a wildcard pattern like `mac*` is expanded to. A pattern
+    /// matching more words only finds the documents containing the first `max_expansions` of
+    /// them. Words that no longer occur in any document are not counted.
+    pub max_expansions: usize,
+    /// The number of changes a durable database logs before it writes a checkpoint, so that
+    /// opening it again only replays the changes logged after the last one. 0 disables automatic
+    /// checkpoints. In-memory databases ignore it.
+    pub checkpoint_interval: usize,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            max_ngram: 1,
+            tokenizer: Arc::new(DefaultTokenizer),
+            stop_words: HashSet::new(),
+            flush_threshold: 1 << 20,
+            deduplicate: false,
+            max_expansions: 1000,
+            checkpoint_interval: 1000,
+        }
+    }
+}
+
+/// One page of the results of a search: the total number of matches, and the matches on the page
+pub type Page<T> = (usize, Vec<T>);
+
+/// The documents found by `Database::search_regex`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegexMatches {
+    /// The ids of the documents containing a matching word, in increasing order
+    pub ids: Vec<usize>,
+    /// The number of matching words the documents were looked up by
+    pub terms: usize,
+    /// Whether more words matched than the expansion limit, so that documents containing only
+    /// the others were left out
+    pub truncated: bool,
+}
+
+/// The occurrences of a single word n-gram within a single document
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Posting {
+    /// The id of the document containing the n-gram
+    pub doc: usize,
+    /// The word offsets at which the n-gram starts in the document, in increasing order
+    pub positions: Vec<usize>,
 }
 
 const BUCKETS: usize = 128;
+/// The BM25 term frequency saturation parameter
+const BM25_K1: f64 = 1.2;
+/// The BM25 document length normalization parameter
+const BM25_B: f64 = 0.75;
+/// Every distinct word contributes several trigrams, so the trigram index gets more buckets
+const TRIGRAM_BUCKETS: usize = 1024;
+/// The name of the write-ahead log inside the data directory of a durable database
+const WAL_FILE: &str = "wal.log";
+/// The name of the directory holding the index segments inside the data directory
+const SEGMENT_DIR: &str = "segments";
+/// The name of the file holding the documents inside the data directory
+const CONTENT_FILE: &str = "content.dat";
+/// The name of the file holding the last checkpoint inside the data directory
+const CHECKPOINT_FILE: &str = "checkpoint.dat";
+/// A text the tokenizer splits into words to tell tokenizers apart in a checkpoint's fingerprint
+const TOKENIZER_SAMPLE: &str = "Running runners RAN to O'Neill's café, naïvely e-mailing 3.14 x_y";
+/// The longest regular expression `search_regex` accepts
+const MAX_REGEX_LEN: usize = 256;
+/// The most memory, in bytes, a compiled regular expression may use
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Separates the field name from the word in the reverse index keys of metadata fields
+const FIELD_SEPARATOR: char = '\0';
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new(DatabaseConfig::default())
+    }
+}
 
 impl Database {
     // TODO:
     // Create a new empty archive. The map should have `BUCKETS` buckets.
-    pub fn new() -> Self {
-        let reverse_index = ConcurrentMultiMap::new(BUCKETS);
-        let blob_store = Mutex::new(Vec::new());
+    pub fn new(config: DatabaseConfig) -> Self {
+        // Keep the documents of an archive that need not outlive the process in an unlinked
+        // temporary file, falling back to memory only if there is nowhere to create one
+        let blob_store = ContentStore::temporary().unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to create a temporary content file, keeping documents in memory: {}",
+                e
+            );
+            ContentStore::memory()
+        });
+        Self::with_store(config, blob_store)
+    }
+
+    // Create a new empty archive that stores its documents in `blob_store`
+    fn with_store(config: DatabaseConfig, blob_store: ContentStore) -> Self {
+        let reverse_index = InvertedIndex::new(BUCKETS);
+        let trigram_index = ConcurrentMultiMap::new(TRIGRAM_BUCKETS);
+        let terms = TermDictionary::new();
+        let doc_lengths = RwLock::new(Vec::new());
+        let metadata = RwLock::new(HashMap::new());
+        let json_documents = RwLock::new(HashSet::new());
+        let stop_words = config
+            .stop_words
+            .iter()
+            .flat_map(|word| config.tokenizer.tokenize(word))
+            .map(|token| token.text)
+            .collect();
+
+        let content_hashes = Mutex::new(HashMap::new());
+        let edit_lock = RwLock::new(());
+        let changes = RwLock::new(());
+
+        Database {
+            reverse_index,
+            trigram_index,
+            terms,
+            blob_store,
+            doc_lengths,
+            metadata,
+            json_documents,
+            stop_words,
+            content_hashes,
+            edit_lock,
+            changes,
+            wal: None,
+            data_dir: None,
+            logged: AtomicUsize::new(0),
+            config,
+        }
+    }
+
+    /// Open a durable archive that keeps a write-ahead log of every change in `data_dir`,
+    /// creating the directory if it does not exist. The changes logged by previous runs are
+    /// replayed first, so the archive comes back with the same documents under the same ids.
+    ///
+    /// The reverse index of a durable archive is written to segment files in the `segments`
+    /// subdirectory as it grows, and every `checkpoint_interval` changes, or when `checkpoint` is
+    /// called, the rest of the archive's state is recorded in a checkpoint. Opening an archive
+    /// with a checkpoint loads its segments and only replays the changes logged after it. Without
+    /// a usable checkpoint, such as one taken with a different n-gram size, tokenizer or stop
+    /// words, the index is rebuilt by replaying the whole log, which is never compacted.
+    pub fn open(config: DatabaseConfig, data_dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        let checkpoint_path = data_dir.join(CHECKPOINT_FILE);
+
+        let resumed = match File::open(&checkpoint_path) {
+            Ok(file) => Checkpoint::read_from(BufReader::new(file))
+                .and_then(|checkpoint| Database::resume(config.clone(), data_dir, checkpoint))
+                .map_err(|e| {
+                    eprintln!(
+                        "Failed to resume from {}, replaying the whole log: {}",
+                        checkpoint_path.display(),
+                        e
+                    )
+                })
+                .ok(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let (mut database, start) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                // The checkpoint must go before the segments it describes are replaced, so that
+                // it is never paired with segments rebuilt from the log
+                if let Err(e) = fs::remove_file(&checkpoint_path) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e);
+                    }
+                }
+                let blob_store = ContentStore::create(&data_dir.join(CONTENT_FILE))?;
+                let mut database = Database::with_store(config, blob_store);
+                database.reverse_index = InvertedIndex::create(
+                    BUCKETS,
+                    &data_dir.join(SEGMENT_DIR),
+                    database.config.flush_threshold,
+                )?;
+                (database, 0)
+            }
+        };
+
+        // Every logged publish added a document, so none of them may be deduplicated now
+        let deduplicate = std::mem::replace(&mut database.config.deduplicate, false);
+        let mut replayed = 0;
+        let wal = WriteAheadLog::open(&data_dir.join(WAL_FILE), start, |request| {
+            match request {
+                Request::Publish { doc } => {
+                    database.publish_with_status(doc)?;
+                }
+                Request::PublishWithMetadata { doc, metadata } => {
+                    database.publish_with_metadata(doc, metadata)?;
+                }
+                Request::PublishJson { doc } => {
+                    database.publish_json(doc)?;
+                }
+                Request::Delete { id } => {
+                    database.delete(id)?;
+                }
+                Request::Update { id, doc } => {
+                    database.update(id, doc)?;
+                }
+                // Only requests that change the archive are logged
+                _ => {}
+            }
+            replayed += 1;
+            Ok(())
+        })?;
+
+        database.config.deduplicate = deduplicate;
+        database.wal = Some(Mutex::new(wal));
+        database.data_dir = Some(data_dir.to_path_buf());
+        database.logged = AtomicUsize::new(replayed);
+        Ok(database)
+    }
+
+    // Open the archive in `data_dir` as it was when `checkpoint` was taken, with the segments
+    // listed in its index manifest, and return it with the length of the log up to the
+    // checkpoint. Fails if the checkpoint was taken with options that index documents
+    // differently, or does not match the files in `data_dir`.
+    fn resume(
+        config: DatabaseConfig,
+        data_dir: &Path,
+        checkpoint: Checkpoint,
+    ) -> io::Result<(Self, u64)> {
+        if checkpoint.fingerprint != fingerprint(&config) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the checkpoint was taken with different indexing options",
+            ));
+        }
+        let log_len = fs::metadata(data_dir.join(WAL_FILE)).map_or(0, |metadata| metadata.len());
+        if log_len < checkpoint.log_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the log is shorter than the checkpoint",
+            ));
+        }
+
+        let extents = checkpoint
+            .documents
+            .iter()
+            .map(|document| document.map(|document| (document.offset, document.len)))
+            .collect();
+        let blob_store = ContentStore::open(
+            &data_dir.join(CONTENT_FILE),
+            checkpoint.content_len,
+            extents,
+        )?;
+        let mut database = Database::with_store(config, blob_store);
+        database.reverse_index = InvertedIndex::open(
+            BUCKETS,
+            &data_dir.join(SEGMENT_DIR),
+            database.config.flush_threshold,
+        )?;
+
+        {
+            let mut doc_lengths = database.doc_lengths.write().unwrap();
+            let mut content_hashes = database.content_hashes.lock().unwrap();
+            for (id, document) in checkpoint.documents.iter().enumerate() {
+                doc_lengths.push(document.map(|document| document.length));
+                if let Some(document) = document {
+                    content_hashes.entry(document.hash).or_default().push(id);
+                }
+            }
+        }
+        database.metadata.write().unwrap().extend(checkpoint.metadata);
+        database.json_documents.write().unwrap().extend(checkpoint.json);
+
+        // Field keys are left out of the trigram index, as they are when publishing
+        database.reverse_index.for_each_key(|key| {
+            if !key.contains(FIELD_SEPARATOR) {
+                database.index_word(key);
+            }
+        });
+
+        Ok((database, checkpoint.log_len))
+    }
+
+    /// Record the state of a durable archive in a checkpoint in its data directory, after
+    /// writing every posting held in memory to segment files, so that opening the archive again
+    /// only replays the changes logged after this. Searches are served while the checkpoint is
+    /// written, but changes to the archive wait until it is done. Does nothing for an in-memory
+    /// archive.
+    pub fn checkpoint(&self) -> io::Result<()> {
+        let _changes = self.changes.write().unwrap();
+        self.write_checkpoint()
+    }
+
+    /// Restore an in-memory archive from a snapshot file written by `snapshot`. The documents are
+    /// not tokenized again, so `config` should match the configuration the snapshot was taken
+    /// with.
+    pub fn restore(config: DatabaseConfig, path: &Path) -> io::Result<Self> {
+        let snapshot = Snapshot::read_from(BufReader::new(File::open(path)?))?;
+
+        let database = Database::new(config);
+        {
+            let mut blob_store = database.blob_store.edit();
+            let mut doc_lengths = database.doc_lengths.write().unwrap();
+            for document in snapshot.documents {
+                match document {
+                    Some((doc, length)) => {
+                        let id = blob_store.push(&doc)?;
+                        database.add_content_hash(&doc, id);
+                        doc_lengths.push(Some(length));
+                    }
+                    None => {
+                        blob_store.push_deleted();
+                        doc_lengths.push(None);
+                    }
+                }
+            }
+        }
 
-        Database{reverse_index, blob_store}
+        // Field keys are left out of the trigram index, as they are when publishing
+        let mut field_keys = HashSet::new();
+        for metadata in snapshot.metadata.values() {
+            field_keys.extend(database.field_positions(metadata.fields()).into_keys());
+        }
+        for &id in snapshot.json.iter() {
+            let doc = database.blob_store.get(id)?.unwrap_or_default();
+            let json = JsonDocument::parse(&doc)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            field_keys.extend(database.field_positions(json.fields).into_keys());
+        }
+
+        for (key, postings) in snapshot.postings {
+            // Snapshots before version 4 joined the field name and word of field keys with ':'
+            let key = match key.split_once(':') {
+                Some((field, word))
+                    if snapshot.version < 4 && field_keys.contains(&field_key(field, word)) =>
+                {
+                    field_key(field, word)
+                }
+                _ => key,
+            };
+            if !field_keys.contains(&key) {
+                database.index_word(&key);
+            }
+            for posting in postings {
+                database.reverse_index.insert(key.clone(), posting);
+            }
+        }
+
+        database.metadata.write().unwrap().extend(snapshot.metadata);
+        database.json_documents.write().unwrap().extend(snapshot.json);
+        Ok(database)
+    }
+
+    /// Write every document and the reverse index to a snapshot file at `path`, replacing any
+    /// file already there. Searches are served while the snapshot is taken, but changes to the
+    /// archive wait until its contents have been copied.
+    pub fn snapshot(&self, path: &Path) -> io::Result<()> {
+        let snapshot = {
+            let _changes = self.changes.write().unwrap();
+
+            let documents = {
+                let doc_lengths = self.doc_lengths.read().unwrap();
+                let mut documents = Vec::with_capacity(doc_lengths.len());
+                for (id, length) in doc_lengths.iter().enumerate() {
+                    documents.push(self.blob_store.get(id)?.zip(*length));
+                }
+                documents
+            };
+
+            let mut postings = self.reverse_index.postings_by_key();
+            postings.retain(|_, key_postings| {
+                key_postings.retain(|posting| matches!(documents.get(posting.doc), Some(Some(_))));
+                !key_postings.is_empty()
+            });
+
+            let metadata = self
+                .metadata
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(&id, metadata)| (id, metadata.clone()))
+                .collect();
+
+            let json = self.json_documents.read().unwrap().iter().copied().collect();
+
+            Snapshot {
+                version: crate::snapshot::VERSION,
+                documents,
+                postings,
+                metadata,
+                json,
+            }
+        };
+
+        // Write to a temporary file first so that a failure never leaves a truncated snapshot. Each
+        // snapshot gets its own temporary file, so snapshots written at the same time to the same
+        // path don't write over each other's.
+        let number = TEMPORARY_SNAPSHOTS.fetch_add(1, Ordering::Relaxed);
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}-{}.tmp", std::process::id(), number));
+        let temporary = path.with_file_name(name);
+
+        let written = File::create(&temporary).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            snapshot.write_to(&mut writer)?;
+            writer.into_inner()?.sync_all()
+        });
+        match written.and_then(|()| fs::rename(&temporary, path)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let _ = fs::remove_file(&temporary);
+                Err(e)
+            }
+        }
     }
 
     // TODO:
@@ -35,32 +513,1146 @@ impl Database {
     //    whitespace is sufficient. It is up to you whether to also perform transformations like
     //    converting to lowercase or removing numerals.
     // 3. Add the document to the blob store
+    //
+    // Words are produced by the configured `Tokenizer`. Each n-gram of them is stored once per
+    // document together with every position it starts at, so that longer phrases can later be
+    // matched by checking that their words occur at consecutive positions. Stop words, and the
+    // n-grams containing them, are skipped, but still count towards the positions of the words
+    // after them.
+    //
+    // If the database deduplicates documents and already holds one with the same content, its id
+    // is returned instead.
+    //
+    // Panics if a durable database fails to write the document or to log it. Use
+    // `publish_with_status` to handle that failure instead.
     pub fn publish(&self, doc: String) -> usize {
+        match self.publish_with_status(doc) {
+            Ok((id, _)) => id,
+            Err(e) => panic!("Failed to publish a document: {}", e),
+        }
+    }
 
-        let unique_id = {
-            let mut blob_store = self.blob_store.lock().unwrap();
-            let id = blob_store.len();
-            blob_store.push(doc.clone());
-            id
+    /// Publish `doc` like `publish`, and also return whether it was deduplicated, that is whether
+    /// the returned id is that of an existing document with the same content. If a durable
+    /// database fails to store or log the document, nothing is published.
+    pub fn publish_with_status(&self, doc: String) -> io::Result<(usize, bool)> {
+        self.publish_with_metadata(doc, Metadata::default())
+    }
+
+    /// Publish `doc` like `publish_with_status`, described by `metadata`. Each field of the
+    /// metadata is indexed on its own, so that queries like `author:austen` can search it. A
+    /// deduplicated document keeps the metadata it was first published with.
+    pub fn publish_with_metadata(&self, doc: String, metadata: Metadata) -> io::Result<(usize, bool)> {
+        self.add(doc, None, metadata)
+    }
+
+    /// Publish `doc`, which must be a JSON object, like `publish_with_status`. Each string value
+    /// is indexed as a field named by its key, so that queries like `author:austen` can search it,
+    /// and is also searchable as text. Retrieving the document returns `doc` as it was published.
+    /// See `JsonDocument` for how the values are named.
+    ///
+    /// A document that is not a JSON object is rejected with an `InvalidData` error wrapping the
+    /// `JsonError` that describes why.
+    pub fn publish_json(&self, doc: String) -> io::Result<(usize, bool)> {
+        let json = JsonDocument::parse(&doc)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.add(doc, Some(json), Metadata::default())
+    }
+
+    /// Replace the text of the document with the given id by `doc` and re-index it, returning
+    /// whether the document exists. The document keeps its id. A document that was published as
+    /// JSON is replaced by plain text, and its fields are no longer searchable. If a durable
+    /// database fails to store or log the new text, the document is left unchanged.
+    pub fn update(&self, id: usize, doc: String) -> io::Result<bool> {
+        self.checkpoint_if_due();
+        let _changes = self.changes.read().unwrap();
+        let _edit = self.edit_lock.write().unwrap();
+
+        let words = self.tokenize(&doc);
+        let indexed = words.iter().filter(|word| !self.is_stop_word(word)).count();
+
+        let (old, json) = {
+            let mut blob_store = self.blob_store.edit();
+            let Some(old) = self.blob_store.get(id)? else {
+                return Ok(false);
+            };
+            let staged = blob_store.write(&doc)?;
+            self.log(|| Request::Update {
+                id,
+                doc: doc.clone(),
+            })?;
+            blob_store.set(id, Some(staged));
+
+            let json = self.json_document(id, &old);
+            self.json_documents.write().unwrap().remove(&id);
+            self.remove_content_hash(&old, id);
+            self.add_content_hash(&doc, id);
+            self.doc_lengths.write().unwrap()[id] = Some(indexed);
+            (old, json)
         };
 
-        for word in doc.split_whitespace() {
-            let word = word.to_lowercase();
-            self.reverse_index.set(word, unique_id);
+        let old_text = json.as_ref().map_or(old.as_str(), |json| json.text.as_str());
+        let old_positions = self.ngram_positions(&self.tokenize(old_text));
+        let mut new_positions = self.ngram_positions(&words);
+
+        // Postings that did not change are left alone so that the document never disappears from
+        // searches for words it contains both before and after the update
+        for (word, positions) in old_positions {
+            match new_positions.get(&word) {
+                Some(new) if *new == positions => {
+                    new_positions.remove(&word);
+                }
+                // Changed postings are replaced when the new ones are inserted below
+                Some(_) => {}
+                None => self.reverse_index.remove(&word, id),
+            }
         }
 
-        unique_id
+        for (word, positions) in new_positions {
+            self.insert_posting(word, Posting { doc: id, positions });
+        }
+
+        if let Some(json) = json {
+            for key in self.field_positions(json.fields).into_keys() {
+                self.reverse_index.remove(&key, id);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Delete the document with the given id, returning whether it existed. The ids of other
+    /// documents are unaffected, and the deleted id is never reused. If a durable database fails
+    /// to log the deletion, the document is left in place.
+    pub fn delete(&self, id: usize) -> io::Result<bool> {
+        self.checkpoint_if_due();
+        let _changes = self.changes.read().unwrap();
+        let _edit = self.edit_lock.write().unwrap();
+
+        let (doc, json) = {
+            let mut blob_store = self.blob_store.edit();
+            let Some(doc) = self.blob_store.get(id)? else {
+                return Ok(false);
+            };
+            self.log(|| Request::Delete { id })?;
+            blob_store.set(id, None);
+
+            let json = self.json_document(id, &doc);
+            self.json_documents.write().unwrap().remove(&id);
+            self.remove_content_hash(&doc, id);
+            self.doc_lengths.write().unwrap()[id] = None;
+            (doc, json)
+        };
+
+        // The document is already tombstoned, so searches stop returning it even before its
+        // postings are gone
+        let text = json.as_ref().map_or(doc.as_str(), |json| json.text.as_str());
+        let words = self.tokenize(text);
+        for word in self.ngram_positions(&words).into_keys() {
+            self.reverse_index.remove(&word, id);
+        }
+
+        if let Some(json) = json {
+            for key in self.field_positions(json.fields).into_keys() {
+                self.reverse_index.remove(&key, id);
+            }
+        }
+
+        if let Some(metadata) = self.metadata.write().unwrap().remove(&id) {
+            for key in self.field_positions(metadata.fields()).into_keys() {
+                self.reverse_index.remove(&key, id);
+            }
+        }
+
+        Ok(true)
     }
     // TODO:
     // Use the reverse index to get the set of documents that contain the given word.
-    pub fn search(&self, word: &str) -> Vec<usize> {
-        self.reverse_index.get(&word.to_lowercase())
+    //
+    // A multi-word phrase only matches documents in which its words occur consecutively. Phrases
+    // no longer than the configured n-gram size are looked up directly. Stop words in the phrase
+    // match any word, and a phrase of only stop words matches nothing.
+    pub fn search(&self, phrase: &str) -> Vec<usize> {
+        let words = self.tokenize(phrase);
+
+        self.phrase_postings(&words)
+            .into_iter()
+            .map(|posting| posting.doc)
+            .collect()
+    }
+
+    /// Find the documents matching the boolean `query`, such as `hamlet AND ghost NOT horatio`,
+    /// in increasing order of id. See `Query` for the syntax.
+    pub fn query(&self, query: &str) -> Result<Vec<usize>, QueryError> {
+        let query = self.parse(query)?;
+        Ok(self.evaluate(&query).into_iter().collect())
+    }
+
+    /// Find one page of the documents matching the boolean `query`: skip the first `offset`
+    /// matches in increasing order of id, and return at most `limit` of the rest, or all of them
+    /// if there is no limit. The total number of matches is returned with the page. Documents
+    /// published later get higher ids, so they only ever show up on later pages.
+    pub fn query_page(
+        &self,
+        query: &str,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Page<usize>, QueryError> {
+        let query = self.parse(query)?;
+        let matches = self.evaluate(&query);
+        let page = page(&matches, offset, limit).collect();
+        Ok((matches.len(), page))
+    }
+
+    /// Find one page of the documents matching the boolean `query` like `query_page` does, each
+    /// with the smallest number of words spanned by the two phrases of a `NEAR/n` operator in
+    /// the query that is not negated. Documents matched without any such operator have no span.
+    pub fn query_spans(
+        &self,
+        query: &str,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Page<(usize, Option<usize>)>, QueryError> {
+        let query = self.parse(query)?;
+        let matches = self.evaluate(&query);
+        let page: Vec<usize> = page(&matches, offset, limit).collect();
+
+        let mut spans = HashMap::new();
+        self.collect_spans(&query, &mut spans);
+        let page = page.into_iter().map(|id| (id, spans.get(&id).copied()));
+        Ok((matches.len(), page.collect()))
+    }
+
+    /// Find one page of the documents matching the boolean `query` like `query_page` does, each
+    /// with a snippet of its text around the first occurrence of a phrase of the query that is
+    /// not negated or restricted to a field. The snippet marks every such occurrence it contains.
+    pub fn search_with_snippets(
+        &self,
+        query: &str,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Page<SearchHit>, QueryError> {
+        let query = self.parse(query)?;
+        let matches = self.evaluate(&query);
+        let page: BTreeSet<usize> = page(&matches, offset, limit).collect();
+
+        // The first word position and word count of every occurrence, by document
+        let mut occurrences: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+        for phrase in query.positive_phrases() {
+            let words = self.tokenize(phrase);
+            for posting in self.phrase_postings(&words) {
+                if page.contains(&posting.doc) {
+                    let doc_occurrences = occurrences.entry(posting.doc).or_default();
+                    doc_occurrences.extend(posting.positions.iter().map(|&p| (p, words.len())));
+                }
+            }
+        }
+
+        let hits = page
+            .into_iter()
+            .filter_map(|id| {
+                let snippet = self.snippet(id, occurrences.remove(&id).unwrap_or_default())?;
+                Some(SearchHit { id, snippet })
+            })
+            .collect();
+        Ok((matches.len(), hits))
+    }
+
+    /// Find the documents matching the boolean `query` like `query` does, but order them by
+    /// decreasing relevance. Each document is paired with its BM25 score, summed over the phrases
+    /// of the query that are not negated.
+    pub fn ranked_search(&self, query: &str) -> Result<Vec<(usize, f64)>, QueryError> {
+        let query = self.parse(query)?;
+        let matches = self.evaluate(&query);
+        if matches.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let doc_lengths = self.doc_lengths.read().unwrap().clone();
+        let doc_count = doc_lengths.iter().flatten().count().max(1) as f64;
+        let average_length = doc_lengths.iter().flatten().sum::<usize>() as f64 / doc_count;
+
+        let mut scores: HashMap<usize, f64> = matches.iter().map(|&doc| (doc, 0.0)).collect();
+        for phrase in query.positive_phrases() {
+            let postings = self.phrase_postings(&self.tokenize(phrase));
+            let frequency = postings.len() as f64;
+            let idf = (1.0 + (doc_count - frequency + 0.5) / (frequency + 0.5)).ln();
+
+            for posting in postings {
+                let Some(score) = scores.get_mut(&posting.doc) else {
+                    continue;
+                };
+
+                let tf = posting.positions.len() as f64;
+                let length = doc_lengths[posting.doc].unwrap_or(0) as f64;
+                let norm = BM25_K1 * (1.0 - BM25_B + BM25_B * length / average_length.max(1.0));
+                *score += idf * tf * (BM25_K1 + 1.0) / (tf + norm);
+            }
+        }
+
+        let mut ranked: Vec<(usize, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+        Ok(ranked)
+    }
+
+    /// Find the documents containing a word that matches the regular expression `pattern` in
+    /// full, ignoring case. Words are matched as they are stored in the index, so `colou?r`
+    /// finds both spellings. The expression is matched against every word in the dictionary,
+    /// but only the first `max_expansions` matching words in sorted order that still occur in a
+    /// document are looked up.
+    ///
+    /// Expressions are compiled to automata that match in linear time, so no expression can
+    /// make the search backtrack. Expressions longer than 256 bytes, or that compile to more than
+    /// a megabyte, are rejected.
+    pub fn search_regex(&self, pattern: &str) -> Result<RegexMatches, QueryError> {
+        if pattern.len() > MAX_REGEX_LEN {
+            return Err(QueryError::Regex(format!(
+                "longer than {} bytes",
+                MAX_REGEX_LEN
+            )));
+        }
+
+        let build = |pattern: &str| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(true)
+                .size_limit(REGEX_SIZE_LIMIT)
+                .dfa_size_limit(REGEX_SIZE_LIMIT)
+                .build()
+                .map_err(|e| QueryError::Regex(e.to_string()))
+        };
+        // Compile the expression on its own first, so that one with unbalanced parentheses like
+        // `a)|(b` is rejected instead of closing the group that anchors it to the whole word
+        build(pattern)?;
+        let regex = build(&format!("^(?:{})$", pattern))?;
+
+        let (terms, truncated) = self
+            .terms
+            .find(self.config.max_expansions, |term| {
+                regex.is_match(term) && self.has_postings(term)
+            });
+
+        let ids: BTreeSet<usize> = terms
+            .iter()
+            .flat_map(|term| self.postings(term))
+            .map(|posting| posting.doc)
+            .collect();
+
+        Ok(RegexMatches {
+            ids: ids.into_iter().collect(),
+            terms: terms.len(),
+            truncated,
+        })
+    }
+
+    /// Find the documents containing a word that is similar to `word`, which may be misspelled or
+    /// only part of a word. Similarity is the fraction of the character trigrams of `word` that
+    /// also occur in an indexed word, and only words with a similarity of at least `threshold`
+    /// are considered. Documents are ranked by the similarity of their best matching word.
+    pub fn fuzzy_search(&self, word: &str, threshold: f64) -> Vec<usize> {
+        let word = self.tokenize(word).concat();
+        let query: HashSet<String> = trigrams(&word).into_iter().collect();
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let mut shared: HashMap<String, usize> = HashMap::new();
+        for trigram in query.iter() {
+            for candidate in self.trigram_index.get(trigram) {
+                *shared.entry(candidate).or_default() += 1;
+            }
+        }
+
+        let mut scores: HashMap<usize, f64> = HashMap::new();
+        for (candidate, count) in shared {
+            let similarity = count as f64 / query.len() as f64;
+            if similarity < threshold {
+                continue;
+            }
+
+            for posting in self.postings(&candidate) {
+                let score = scores.entry(posting.doc).or_default();
+                *score = score.max(similarity);
+            }
+        }
+
+        let mut ranked: Vec<(usize, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+        ranked.into_iter().map(|(doc, _)| doc).collect()
+    }
+
+    /// Suggest up to `limit` indexed words that are spelled like the words of `query` that occur
+    /// in no document, for a query that found nothing. A word is suggested if at most one edit,
+    /// or two for words longer than four characters, turns a missing word into it, where an edit
+    /// inserts, deletes or replaces a character or swaps two adjacent ones. Suggestions are
+    /// ordered by the number of edits, and then from the most to the least frequent word.
+    pub fn suggest(&self, query: &str, limit: usize) -> Vec<String> {
+        let Ok(query) = self.parse(query) else {
+            return Vec::new();
+        };
+
+        let mut missing: Vec<Vec<char>> = Vec::new();
+        for phrase in query.positive_phrases() {
+            for word in self.tokenize(phrase) {
+                if !self.is_stop_word(&word) && self.postings(&word).is_empty() {
+                    missing.push(word.chars().collect());
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Vec::new();
+        }
+
+        let mut candidates: Vec<(usize, String)> = Vec::new();
+        self.terms.for_each(|term| {
+            let term_chars: Vec<char> = term.chars().collect();
+            let distance = missing
+                .iter()
+                .filter_map(|word| {
+                    let max_distance = if word.len() > 4 { 2 } else { 1 };
+                    if word.len().abs_diff(term_chars.len()) > max_distance {
+                        return None;
+                    }
+                    let distance = edit_distance(word, &term_chars);
+                    (distance <= max_distance).then_some(distance)
+                })
+                .min();
+            if let Some(distance) = distance {
+                candidates.push((distance, term.clone()));
+            }
+        });
+
+        // Words that no longer occur in any document stay in the dictionary, but are not
+        // worth suggesting
+        let mut ranked: Vec<(usize, usize, String)> = candidates
+            .into_iter()
+            .map(|(distance, term)| {
+                let frequency = self
+                    .postings(&term)
+                    .iter()
+                    .map(|posting| posting.positions.len())
+                    .sum();
+                (distance, frequency, term)
+            })
+            .filter(|&(_, frequency, _)| frequency > 0)
+            .collect();
+
+        ranked.sort_by(|(a_distance, a_frequency, a), (b_distance, b_frequency, b)| {
+            a_distance
+                .cmp(b_distance)
+                .then(b_frequency.cmp(a_frequency))
+                .then(a.cmp(b))
+        });
+        ranked.truncate(limit);
+        ranked.into_iter().map(|(_, _, term)| term).collect()
+    }
+
+    /// Count the occurrences of the phrase `ngram` in every document. Phrases longer than the
+    /// configured n-gram size are counted too, by matching their words at consecutive positions,
+    /// and stop words in the phrase match any word. A phrase of only stop words cannot be counted,
+    /// since stop words are not indexed.
+    pub fn ngram_stats(&self, ngram: &str) -> Result<NgramStats, QueryError> {
+        let words = self.tokenize(ngram);
+        if words.is_empty() {
+            return Ok(NgramStats::default());
+        }
+        if words.iter().all(|word| self.is_stop_word(word)) {
+            return Err(QueryError::StopWord(ngram.to_string()));
+        }
+
+        let mut counts: Vec<(usize, usize)> = self
+            .phrase_postings(&words)
+            .into_iter()
+            .map(|posting| (posting.doc, posting.positions.len()))
+            .collect();
+        counts.sort_unstable();
+
+        Ok(NgramStats {
+            total: counts.iter().map(|(_, count)| count).sum(),
+            documents: counts.len(),
+            counts,
+        })
+    }
+
+    /// Find the `k` most frequent n-grams of `n` words across all documents, with their numbers
+    /// of occurrences, from the most to the least frequent. Only the n-grams stored in the reverse
+    /// index are counted, so `n` must be between 1 and the configured n-gram size. Every key of
+    /// the reverse index is visited, so this takes time proportional to the size of the index.
+    pub fn top_ngrams(&self, n: usize, k: usize) -> Result<Vec<(String, usize)>, QueryError> {
+        if !(1..=self.max_ngram()).contains(&n) {
+            return Err(QueryError::NgramSize {
+                n,
+                max_ngram: self.max_ngram(),
+            });
+        }
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+
+        // Keep the `k` most frequent n-grams seen so far in a heap whose top is the least
+        // frequent of them, and sorted last of those that occur equally often
+        let mut top = BinaryHeap::new();
+        self.reverse_index.for_each_key(|key| {
+            if key.contains(FIELD_SEPARATOR) || key.matches(' ').count() + 1 != n {
+                return;
+            }
+            let count = self
+                .postings(key)
+                .iter()
+                .map(|posting| posting.positions.len())
+                .sum();
+            if count > 0 {
+                top.push(Reverse((count, Reverse(key.to_string()))));
+                if top.len() > k {
+                    top.pop();
+                }
+            }
+        });
+
+        Ok(top
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((count, Reverse(ngram)))| (ngram, count))
+            .collect())
+    }
+
+    /// Measure the size of the reverse index, including the memory its compressed posting lists
+    /// use compared to storing every posting separately
+    pub fn index_stats(&self) -> IndexStats {
+        self.reverse_index.stats()
     }
+
+    /// Retrieve the metadata of the document with the given id, which is empty if it was
+    /// published without any. Return None if the given id is invalid.
+    pub fn metadata(&self, id: usize) -> Option<Metadata> {
+        if !matches!(self.doc_lengths.read().unwrap().get(id), Some(Some(_))) {
+            return None;
+        }
+
+        let metadata = self.metadata.read().unwrap();
+        Some(metadata.get(&id).cloned().unwrap_or_default())
+    }
+
     // TODO:
     // Retrieve the document with the given id from the blob store.
     // Return None if the given id is invalid.
     pub fn retrieve(&self, id: usize) -> Option<String> {
-        let blob_store = self.blob_store.lock().unwrap();
-        blob_store.get(id).cloned()
+        match self.blob_store.get(id) {
+            Ok(doc) => doc,
+            Err(e) => {
+                eprintln!("Failed to read document {}: {}", id, e);
+                None
+            }
+        }
+    }
+
+    // Add `doc` to the archive, indexing the string values of `json` instead of `doc` if it was
+    // published as JSON, and the fields of `metadata`.
+    fn add(
+        &self,
+        doc: String,
+        json: Option<JsonDocument>,
+        metadata: Metadata,
+    ) -> io::Result<(usize, bool)> {
+        self.checkpoint_if_due();
+        let _changes = self.changes.read().unwrap();
+        let _publish = self.edit_lock.read().unwrap();
+
+        let text = json.as_ref().map_or(doc.as_str(), |json| json.text.as_str());
+        let words = self.tokenize(text);
+        let indexed = words.iter().filter(|word| !self.is_stop_word(word)).count();
+
+        let unique_id = {
+            let mut blob_store = self.blob_store.edit();
+            if self.config.deduplicate {
+                if let Some(id) = self.find_duplicate(&doc, json.is_some()) {
+                    return Ok((id, true));
+                }
+            }
+
+            // Writing the text is the only step that can fail besides logging, and the text
+            // stays invisible until the change has been logged
+            let staged = blob_store.write(&doc)?;
+            self.log(|| {
+                let doc = doc.clone();
+                if json.is_some() {
+                    Request::PublishJson { doc }
+                } else if metadata.is_empty() {
+                    Request::Publish { doc }
+                } else {
+                    Request::PublishWithMetadata {
+                        doc,
+                        metadata: metadata.clone(),
+                    }
+                }
+            })?;
+            let id = blob_store.insert(staged);
+
+            self.add_content_hash(&doc, id);
+            if json.is_some() {
+                self.json_documents.write().unwrap().insert(id);
+            }
+            self.doc_lengths.write().unwrap().push(Some(indexed));
+            id
+        };
+
+        for (word, positions) in self.ngram_positions(&words) {
+            self.insert_posting(word, Posting { doc: unique_id, positions });
+        }
+
+        let fields = match json {
+            Some(json) => self.field_positions(json.fields),
+            None => self.field_positions(metadata.fields()),
+        };
+        for (key, positions) in fields {
+            let posting = Posting { doc: unique_id, positions };
+            self.reverse_index.insert(key, posting);
+        }
+        if !metadata.is_empty() {
+            self.metadata.write().unwrap().insert(unique_id, metadata);
+        }
+
+        Ok((unique_id, false))
+    }
+
+    // Cut a snippet from the indexed text of the document with the given id around the given
+    // occurrences, each a word position and a number of words. Return None if there is no such
+    // document.
+    fn snippet(&self, id: usize, mut occurrences: Vec<(usize, usize)>) -> Option<Snippet> {
+        let doc = self.retrieve(id)?;
+        let json = self.json_document(id, &doc);
+        let text = json.as_ref().map_or(doc.as_str(), |json| json.text.as_str());
+
+        let tokens = self.config.tokenizer.tokenize(text);
+        occurrences.sort_unstable();
+        let matches: Vec<(usize, usize)> = occurrences
+            .into_iter()
+            .filter_map(|(position, words)| {
+                let first = tokens.get(position)?;
+                let last = tokens.get(position + words.max(1) - 1)?;
+                Some((first.start, last.end))
+            })
+            .collect();
+
+        Some(Snippet::new(text, &matches))
+    }
+
+    // Parse the text of the document with the given id if it was published as JSON.
+    fn json_document(&self, id: usize, doc: &str) -> Option<JsonDocument> {
+        if !self.json_documents.read().unwrap().contains(&id) {
+            return None;
+        }
+        // Only valid JSON documents are ever marked as such
+        JsonDocument::parse(doc).ok()
+    }
+
+    // Parse `query`, rejecting it if one of its phrases could never match because it consists
+    // only of stop words.
+    fn parse(&self, query: &str) -> Result<Query, QueryError> {
+        let query = Query::parse(query)?;
+
+        for phrase in query.phrases() {
+            if self.tokenize(phrase).iter().all(|word| self.is_stop_word(word)) {
+                return Err(QueryError::StopWord(phrase.to_string()));
+            }
+        }
+
+        Ok(query)
+    }
+
+    fn evaluate(&self, query: &Query) -> BTreeSet<usize> {
+        match query {
+            Query::Phrase(phrase) => self.search(phrase).into_iter().collect(),
+            Query::And(left, right) => {
+                let left = self.evaluate(left);
+                if left.is_empty() {
+                    return left;
+                }
+
+                // Subtracting from the left side avoids materializing every document id
+                match right.as_ref() {
+                    Query::Not(right) => &left - &self.evaluate(right),
+                    right => &left & &self.evaluate(right),
+                }
+            }
+            Query::Or(left, right) => &self.evaluate(left) | &self.evaluate(right),
+            Query::Field { field, phrase } => self
+                .field_postings(field, &self.tokenize(phrase))
+                .into_iter()
+                .map(|posting| posting.doc)
+                .collect(),
+            Query::Near {
+                left,
+                right,
+                distance,
+            } => self.near_spans(left, right, *distance).into_keys().collect(),
+            Query::Wildcard(pattern) => self
+                .terms
+                .expand(&pattern.to_lowercase(), self.config.max_expansions, |term| {
+                    self.has_postings(term)
+                })
+                .into_iter()
+                .flat_map(|term| self.postings(&term))
+                .map(|posting| posting.doc)
+                .collect(),
+            Query::Not(query) => {
+                let all: BTreeSet<usize> = self
+                    .doc_lengths
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(id, length)| length.map(|_| id))
+                    .collect();
+                &all - &self.evaluate(query)
+            }
+        }
+    }
+
+    // Record in `spans` the smallest span of every document matched by a `NEAR/n` operator in
+    // `query` that is not negated.
+    fn collect_spans(&self, query: &Query, spans: &mut HashMap<usize, usize>) {
+        match query {
+            Query::Near {
+                left,
+                right,
+                distance,
+            } => {
+                for (doc, span) in self.near_spans(left, right, *distance) {
+                    let best = spans.entry(doc).or_insert(span);
+                    *best = (*best).min(span);
+                }
+            }
+            Query::And(left, right) | Query::Or(left, right) => {
+                self.collect_spans(left, spans);
+                self.collect_spans(right, spans);
+            }
+            Query::Phrase(_) | Query::Not(_) | Query::Field { .. } | Query::Wildcard(_) => {}
+        }
+    }
+
+    // Find the documents in which the phrases `left` and `right` occur, in either order, with at
+    // most `distance` words between them, mapped to the smallest number of words a pair of such
+    // occurrences spans.
+    fn near_spans(&self, left: &str, right: &str, distance: usize) -> HashMap<usize, usize> {
+        let left_len = self.tokenize(left).len();
+        let right_len = self.tokenize(right).len();
+        let right: HashMap<usize, Vec<usize>> = self
+            .phrase_postings(&self.tokenize(right))
+            .into_iter()
+            .map(|posting| (posting.doc, posting.positions))
+            .collect();
+
+        let mut spans = HashMap::new();
+        for posting in self.phrase_postings(&self.tokenize(left)) {
+            let Some(right_positions) = right.get(&posting.doc) else {
+                continue;
+            };
+
+            let mut best: Option<usize> = None;
+            for &start in posting.positions.iter() {
+                // Only right occurrences starting in this window can be close enough
+                let first = start.saturating_sub(right_len + distance);
+                let last = start + left_len + distance;
+                let from = right_positions.partition_point(|&position| position < first);
+
+                for &right_start in right_positions[from..].iter() {
+                    if right_start > last {
+                        break;
+                    }
+                    let left_end = start + left_len;
+                    let right_end = right_start + right_len;
+                    // The two phrases must not share any words
+                    let gap = if left_end <= right_start {
+                        right_start - left_end
+                    } else if right_end <= start {
+                        start - right_end
+                    } else {
+                        continue;
+                    };
+                    if gap <= distance {
+                        let span = left_end.max(right_end) - start.min(right_start);
+                        best = Some(best.map_or(span, |best| best.min(span)));
+                    }
+                }
+            }
+
+            if let Some(span) = best {
+                spans.insert(posting.doc, span);
+            }
+        }
+        spans
+    }
+
+    // Find every occurrence of the phrase `words`. The returned postings hold, for each document
+    // containing the phrase, the positions at which the phrase starts.
+    fn phrase_postings(&self, words: &[String]) -> Vec<Posting> {
+        let has_stop_words = words.iter().any(|word| self.is_stop_word(word));
+        if !has_stop_words && (1..=self.max_ngram()).contains(&words.len()) {
+            return self.postings(&words.join(" "));
+        }
+
+        let indexed = words
+            .iter()
+            .enumerate()
+            .filter(|(_, word)| !self.is_stop_word(word))
+            .map(|(offset, word)| (offset, word.clone()));
+        self.chain_postings(indexed)
+    }
+
+    // Find every occurrence of the phrase `words` in the metadata field `field`.
+    fn field_postings(&self, field: &str, words: &[String]) -> Vec<Posting> {
+        let keys = words
+            .iter()
+            .enumerate()
+            .map(|(offset, word)| (offset, field_key(field, word)));
+        self.chain_postings(keys)
+    }
+
+    // Find the documents in which every key occurs at its offset from the start of a phrase. The
+    // returned postings hold the positions at which the phrase starts.
+    fn chain_postings(&self, keys: impl IntoIterator<Item = (usize, String)>) -> Vec<Posting> {
+        let mut keys = keys.into_iter();
+        let Some((first_offset, first)) = keys.next() else {
+            return Vec::new();
+        };
+
+        let mut candidates = self.postings(&first);
+
+        for (offset, key) in keys {
+            if candidates.is_empty() {
+                break;
+            }
+            let distance = offset - first_offset;
+
+            let next: HashMap<usize, Vec<usize>> = self
+                .postings(&key)
+                .into_iter()
+                .map(|posting| (posting.doc, posting.positions))
+                .collect();
+
+            candidates.retain_mut(|candidate| match next.get(&candidate.doc) {
+                Some(positions) => {
+                    candidate
+                        .positions
+                        .retain(|start| positions.binary_search(&(start + distance)).is_ok());
+                    !candidate.positions.is_empty()
+                }
+                None => false,
+            });
+        }
+
+        // Leading stop words shift the start of the phrase before its first indexed word
+        if first_offset > 0 {
+            candidates.retain_mut(|candidate| {
+                candidate.positions.retain(|&start| start >= first_offset);
+                for start in candidate.positions.iter_mut() {
+                    *start -= first_offset;
+                }
+                !candidate.positions.is_empty()
+            });
+        }
+
+        candidates
+    }
+
+    // Find a document with the same content as `doc`, published as JSON if `json` is set.
+    // Documents are first matched by hash, and then compared in full in case of a collision.
+    fn find_duplicate(&self, doc: &str, json: bool) -> Option<usize> {
+        let candidates = self
+            .content_hashes
+            .lock()
+            .unwrap()
+            .get(&content_hash(doc))
+            .cloned()
+            .unwrap_or_default();
+
+        candidates.into_iter().find(|&id| {
+            self.json_documents.read().unwrap().contains(&id) == json
+                && self.retrieve(id).as_deref() == Some(doc)
+        })
+    }
+
+    fn add_content_hash(&self, doc: &str, id: usize) {
+        let mut content_hashes = self.content_hashes.lock().unwrap();
+        content_hashes.entry(content_hash(doc)).or_default().push(id);
+    }
+
+    fn remove_content_hash(&self, doc: &str, id: usize) {
+        let mut content_hashes = self.content_hashes.lock().unwrap();
+        let hash = content_hash(doc);
+        if let Some(ids) = content_hashes.get_mut(&hash) {
+            ids.retain(|&other| other != id);
+            if ids.is_empty() {
+                content_hashes.remove(&hash);
+            }
+        }
+    }
+
+    // Append the change built by `request` to the write-ahead log, if there is one. Callers keep
+    // the blob store locked for editing so that changes are logged in the order they are applied,
+    // and log a change before applying any of it, so that a change that fails to be logged is not
+    // made at all.
+    fn log(&self, request: impl FnOnce() -> Request) -> io::Result<()> {
+        match &self.wal {
+            Some(wal) => {
+                wal.lock().unwrap().append(&request())?;
+                self.logged.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Write a checkpoint once `checkpoint_interval` changes have been logged since the last one.
+    // A checkpoint that fails is retried after as many changes again.
+    fn checkpoint_if_due(&self) {
+        let interval = self.config.checkpoint_interval;
+        let due = || interval > 0 && self.logged.load(Ordering::Relaxed) >= interval;
+        if self.wal.is_none() || !due() {
+            return;
+        }
+
+        let _changes = self.changes.write().unwrap();
+        // Another change may have written the checkpoint while this one waited for the lock
+        if due() {
+            if let Err(e) = self.write_checkpoint() {
+                eprintln!("Failed to write a checkpoint: {}", e);
+            }
+        }
+    }
+
+    // Flush the reverse index and record everything else in a checkpoint file, replacing the
+    // previous one. The caller holds `changes` for writing, so that no change is half-applied.
+    fn write_checkpoint(&self) -> io::Result<()> {
+        let (Some(wal), Some(data_dir)) = (&self.wal, &self.data_dir) else {
+            return Ok(());
+        };
+        self.logged.store(0, Ordering::Relaxed);
+
+        // The checkpoint may only point at segments and texts that are already on disk
+        self.reverse_index.flush()?;
+        self.blob_store.sync()?;
+
+        let log_len = wal.lock().unwrap().size();
+        let (content_len, extents) = self.blob_store.layout();
+        let hashes: HashMap<usize, u64> = self
+            .content_hashes
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(&hash, ids)| ids.iter().map(move |&id| (id, hash)))
+            .collect();
+        let doc_lengths = self.doc_lengths.read().unwrap();
+        let documents = extents
+            .into_iter()
+            .enumerate()
+            .map(|(id, extent)| {
+                let (offset, len) = extent?;
+                Some(CheckpointDocument {
+                    offset,
+                    len,
+                    length: doc_lengths.get(id).copied().flatten()?,
+                    hash: *hashes.get(&id)?,
+                })
+            })
+            .collect();
+
+        let checkpoint = Checkpoint {
+            fingerprint: fingerprint(&self.config),
+            log_len,
+            content_len,
+            documents,
+            metadata: self
+                .metadata
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(&id, metadata)| (id, metadata.clone()))
+                .collect(),
+            json: self.json_documents.read().unwrap().iter().copied().collect(),
+        };
+
+        let path = data_dir.join(CHECKPOINT_FILE);
+        let temporary = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        checkpoint.write_to(&mut writer)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temporary, &path)
+    }
+
+    // Add `posting` to the reverse index under `key`, and index `key` as a word.
+    fn insert_posting(&self, key: String, posting: Posting) {
+        self.index_word(&key);
+        self.reverse_index.insert(key, posting);
+    }
+
+    // Map each trigram of `key` to `key` in the trigram index and add `key` to the term
+    // dictionary, if `key` is a single word.
+    fn index_word(&self, key: &str) {
+        if !key.contains(' ') {
+            for trigram in trigrams(key) {
+                self.trigram_index.set(trigram, key.to_string());
+            }
+            self.terms.insert(key);
+        }
+    }
+
+    // Look up the postings of `key` in the reverse index, leaving out deleted documents whose
+    // postings have not been removed yet.
+    fn postings(&self, key: &str) -> Vec<Posting> {
+        let mut postings = self.reverse_index.get(key);
+        let doc_lengths = self.doc_lengths.read().unwrap();
+        postings.retain(|posting| matches!(doc_lengths.get(posting.doc), Some(Some(_))));
+        postings
+    }
+
+    // Whether `key` occurs in any document that has not been deleted. Words stay in the term
+    // dictionary after the last document containing them is gone.
+    fn has_postings(&self, key: &str) -> bool {
+        !self.postings(key).is_empty()
+    }
+
+    // Compute the positions of every indexed n-gram of `words`. Stop words, and the n-grams
+    // containing them, are skipped.
+    fn ngram_positions(&self, words: &[String]) -> HashMap<String, Vec<usize>> {
+        let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+        for n in 1..=self.max_ngram() {
+            for (position, ngram) in words.windows(n).enumerate() {
+                if ngram.iter().any(|word| self.is_stop_word(word)) {
+                    continue;
+                }
+                positions.entry(ngram.join(" ")).or_default().push(position);
+            }
+        }
+        positions
+    }
+
+    // Compute the positions of every word of every field in `fields`, keyed by field and word.
+    // Fields are indexed in full, stop words included. The values of a field that occurs more than
+    // once, like tags, are kept a word apart so that a phrase never spans two of them.
+    fn field_positions<F: AsRef<str>>(
+        &self,
+        fields: impl IntoIterator<Item = (F, String)>,
+    ) -> HashMap<String, Vec<usize>> {
+        let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut next: HashMap<String, usize> = HashMap::new();
+        for (field, value) in fields {
+            let field = field.as_ref();
+            let start = next.entry(field.to_string()).or_default();
+            let words = self.tokenize(&value);
+            for (offset, word) in words.iter().enumerate() {
+                positions
+                    .entry(field_key(field, word))
+                    .or_default()
+                    .push(*start + offset);
+            }
+            *start += words.len() + 1;
+        }
+        positions
     }
+
+    fn is_stop_word(&self, word: &str) -> bool {
+        self.stop_words.contains(word)
+    }
+
+    // Split `text` into the normalized words that are stored in the reverse index. The field
+    // separator is removed from words, as it marks the keys of metadata fields.
+    fn tokenize(&self, text: &str) -> Vec<String> {
+        self.config
+            .tokenizer
+            .tokenize(text)
+            .into_iter()
+            .map(|token| match token.text.contains(FIELD_SEPARATOR) {
+                true => token.text.replace(FIELD_SEPARATOR, ""),
+                false => token.text,
+            })
+            .collect()
+    }
+
+    fn max_ngram(&self) -> usize {
+        self.config.max_ngram.max(1)
+    }
+}
+
+// The reverse index key under which `word` is stored for the metadata field `field`. Words are
+// stripped of `FIELD_SEPARATOR` when they are tokenized, so whatever the tokenizer, these keys
+// cannot collide with the words of a document's text.
+fn field_key(field: &str, word: &str) -> String {
+    format!("{}{}{}", field, FIELD_SEPARATOR, word)
+}
+
+// A hash of the options that decide which keys the documents are indexed under, which must match
+// for the segments of a checkpoint to be reused. A tokenizer cannot be compared directly, so the
+// words it splits a sample text into are hashed instead. Content hashes use the same hasher, so
+// if its algorithm ever changes, the fingerprint changes with it.
+fn fingerprint(config: &DatabaseConfig) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    crate::checkpoint::VERSION.hash(&mut hasher);
+    config.max_ngram.max(1).hash(&mut hasher);
+
+    let stop_words: BTreeSet<String> = config
+        .stop_words
+        .iter()
+        .flat_map(|word| config.tokenizer.tokenize(word))
+        .map(|token| token.text)
+        .collect();
+    stop_words.hash(&mut hasher);
+    for token in config.tokenizer.tokenize(TOKENIZER_SAMPLE) {
+        (token.text, token.start, token.end).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// The ids in `matches` after skipping the first `offset`, up to `limit` of them.
+fn page(
+    matches: &BTreeSet<usize>,
+    offset: usize,
+    limit: Option<usize>,
+) -> impl Iterator<Item = usize> + '_ {
+    matches
+        .iter()
+        .copied()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+}
+
+// The number of insertions, deletions, substitutions and swaps of adjacent characters needed to
+// turn `a` into `b`, counting each character at most once.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    // Only the last two rows of the table are needed at a time
+    let mut before: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut previous, current);
+    }
+    previous[b.len()]
+}
+
+// Hash the content of a document for deduplication.
+fn content_hash(doc: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    doc.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Split `word` into its overlapping three-character windows. The word is padded with `$` on both
+// sides so that its first and last characters carry extra weight and short words still produce
+// at least one trigram.
+fn trigrams(word: &str) -> Vec<String> {
+    if word.is_empty() {
+        return Vec::new();
+    }
+
+    let chars: Vec<char> = format!("${}$", word).chars().collect();
+    chars.windows(3).map(|window| window.iter().collect()).collect()
 }
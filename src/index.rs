@@ -0,0 +1,566 @@
+use crate::database::Posting;
+use crate::posting::{PostingStats, PostingTable};
+use crate::segment::{Segment, SegmentWriter};
+use crate::snapshot::{read_string, read_varint, write_string, write_varint};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+// The reverse index is a log-structured merge tree. New postings go to an in-memory table, which
+// is frozen and written to disk as an immutable segment file, sorted by key, once it holds enough
+// postings. A lookup reads the table and then the segments from newest to oldest, and the newest
+// posting for each document wins. Removing a posting that may already have been written to a
+// segment therefore stores a tombstone, a posting without positions, in the table to hide it.
+// Whenever `MERGE_FACTOR` segments have piled up, a background thread merges them into one,
+// dropping the tombstones together with the postings they hide.
+//
+// The segments in use are listed in a manifest file in the index directory, which is rewritten
+// whenever a flush or merge changes them, before the files of merged segments are removed. An
+// index opened again loads the segments in its manifest, and removes any other segment file, like
+// one a merge was writing when the process stopped.
+//
+// An index that is not given a directory never flushes its table, and keeps everything in memory.
+
+/// The number of segments that triggers a merge
+const MERGE_FACTOR: usize = 4;
+/// The extension of segment files in the index directory
+const SEGMENT_EXTENSION: &str = "seg";
+/// The name of the file listing the segments in use
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_MAGIC: &[u8; 8] = b"NGRAMMAN";
+const MANIFEST_VERSION: u32 = 1;
+
+/// A map from keys to the postings of the documents containing them, kept partly in memory and
+/// partly in segment files
+pub struct InvertedIndex {
+    shared: Arc<Shared>,
+    /// The thread merging segments, if one has been started
+    merger: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// The size of an index
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IndexStats {
+    /// The memory used by the postings that have not been written to a segment yet
+    pub memory: PostingStats,
+    /// The number of segment files
+    pub segments: usize,
+    /// The total size in bytes of the segment files
+    pub segment_bytes: u64,
+}
+
+// The parts of the index shared with the merging thread
+struct Shared {
+    /// The number of buckets of each in-memory table
+    buckets: usize,
+    layers: RwLock<Layers>,
+    /// Where segments are written, if the index is stored on disk
+    storage: Option<Storage>,
+}
+
+struct Layers {
+    /// The table that new postings are added to
+    memtable: Arc<PostingTable>,
+    /// Tables that are full but not yet written to a segment, oldest first
+    frozen: Vec<Arc<PostingTable>>,
+    /// The segments, oldest first
+    segments: Vec<Arc<Segment>>,
+}
+
+struct Storage {
+    dir: PathBuf,
+    /// The number of postings the table holds before it is written to a segment
+    flush_threshold: usize,
+    /// The number of postings and tombstones added to the table since it was last frozen
+    unflushed: AtomicUsize,
+    /// Held while frozen tables are written to segments, so that they are written in order
+    flush_lock: Mutex<()>,
+    /// Held while the manifest is written, so that an older list never replaces a newer one
+    manifest_lock: Mutex<()>,
+    /// Set while a merging thread is running
+    merging: AtomicBool,
+    /// The number used to name the next segment file
+    next_segment: AtomicU64,
+}
+
+impl Layers {
+    // Whether anything older than the table could hold postings that a removal has to hide
+    fn has_older(&self) -> bool {
+        !self.frozen.is_empty() || !self.segments.is_empty()
+    }
+}
+
+impl InvertedIndex {
+    /// Create an empty index that is kept entirely in memory, in a table with `buckets` buckets
+    pub fn new(buckets: usize) -> Self {
+        Self::with_storage(buckets, None)
+    }
+
+    /// Create an empty index that writes its postings to segment files in `dir` once more than
+    /// `flush_threshold` of them are held in memory. The directory is created if it does not
+    /// exist, and any segments left in it are removed.
+    pub fn create(buckets: usize, dir: &Path, flush_threshold: usize) -> io::Result<Self> {
+        Self::open_with(buckets, dir, flush_threshold, Vec::new())
+    }
+
+    /// Open the index stored in `dir` by a previous run, loading the segments listed in its
+    /// manifest, like `create` otherwise. Fails if there is no manifest or a segment in it cannot
+    /// be opened.
+    pub fn open(buckets: usize, dir: &Path, flush_threshold: usize) -> io::Result<Self> {
+        let names = read_manifest(&dir.join(MANIFEST_FILE))?;
+        Self::open_with(buckets, dir, flush_threshold, names)
+    }
+
+    fn open_with(
+        buckets: usize,
+        dir: &Path,
+        flush_threshold: usize,
+        names: Vec<String>,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let segments = names
+            .iter()
+            .map(|name| Segment::open(&dir.join(name)).map(Arc::new))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut next_segment = 0;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_none_or(|extension| extension != SEGMENT_EXTENSION)
+            {
+                continue;
+            }
+            let listed = path
+                .file_name()
+                .is_some_and(|name| names.iter().any(|listed| name == listed.as_str()));
+            if listed {
+                let number = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str()?.parse().ok());
+                next_segment = next_segment.max(number.map_or(0, |number: u64| number + 1));
+            } else {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let storage = Storage {
+            dir: dir.to_path_buf(),
+            flush_threshold: flush_threshold.max(1),
+            unflushed: AtomicUsize::new(0),
+            flush_lock: Mutex::new(()),
+            manifest_lock: Mutex::new(()),
+            merging: AtomicBool::new(false),
+            next_segment: AtomicU64::new(next_segment),
+        };
+        let index = Self::with_storage(buckets, Some(storage));
+        index.shared.layers.write().unwrap().segments = segments;
+        index.shared.write_manifest()?;
+        Ok(index)
+    }
+
+    fn with_storage(buckets: usize, storage: Option<Storage>) -> Self {
+        let layers = Layers {
+            memtable: Arc::new(PostingTable::new(buckets)),
+            frozen: Vec::new(),
+            segments: Vec::new(),
+        };
+
+        InvertedIndex {
+            shared: Arc::new(Shared {
+                buckets,
+                layers: RwLock::new(layers),
+                storage,
+            }),
+            merger: Mutex::new(None),
+        }
+    }
+
+    /// Add `posting` under `key`, replacing any previous posting of the same document
+    pub fn insert(&self, key: String, posting: Posting) {
+        {
+            // The table is only swapped out under the write lock, so holding the read lock
+            // guarantees the posting lands in a table that has not been frozen yet
+            let layers = self.shared.layers.read().unwrap();
+            layers.memtable.insert(key, posting);
+        }
+        self.added();
+    }
+
+    /// Remove the posting of document `doc` from under `key`
+    pub fn remove(&self, key: &str, doc: usize) {
+        {
+            let layers = self.shared.layers.read().unwrap();
+            if !layers.has_older() {
+                layers.memtable.remove(key, doc);
+                return;
+            }
+            layers.memtable.insert(key.to_string(), tombstone(doc));
+        }
+        self.added();
+    }
+
+    /// Look up the postings of `key`, newest first
+    pub fn get(&self, key: &str) -> Vec<Posting> {
+        let (tables, segments) = {
+            let layers = self.shared.layers.read().unwrap();
+            let mut tables = vec![Arc::clone(&layers.memtable)];
+            tables.extend(layers.frozen.iter().rev().cloned());
+            (tables, layers.segments.clone())
+        };
+
+        let mut seen = HashSet::new();
+        let mut postings = Vec::new();
+        let mut visit = |candidates: Vec<Posting>| {
+            for posting in candidates {
+                if seen.insert(posting.doc) && !posting.positions.is_empty() {
+                    postings.push(posting);
+                }
+            }
+        };
+
+        for table in tables.iter() {
+            visit(table.get(key));
+        }
+        for segment in segments.iter().rev() {
+            match segment.get(key) {
+                Ok(candidates) => visit(candidates),
+                Err(e) => eprintln!("Failed to read the postings of \"{}\": {}", key, e),
+            }
+        }
+
+        postings
+    }
+
+    /// Every key in the index with its postings, sorted by document id
+    pub fn postings_by_key(&self) -> BTreeMap<String, Vec<Posting>> {
+        let mut postings_by_key = BTreeMap::new();
+        self.for_each_key(|key| {
+            let mut postings = self.get(key);
+            postings.sort_by_key(|posting| posting.doc);
+            if !postings.is_empty() {
+                postings_by_key.insert(key.to_string(), postings);
+            }
+        });
+        postings_by_key
+    }
+
+    /// Call `f` with every key that has postings or tombstones in the index, once each, in sorted
+    /// order. The keys of the segments are merged as they are visited, so only the keys held in
+    /// memory are collected first.
+    pub fn for_each_key<F: FnMut(&str)>(&self, mut f: F) {
+        let mut table_keys = BTreeSet::new();
+        let segments = {
+            let layers = self.shared.layers.read().unwrap();
+            for table in std::iter::once(&layers.memtable).chain(layers.frozen.iter()) {
+                table.for_each(|key, _| {
+                    table_keys.insert(key.clone());
+                });
+            }
+            layers.segments.clone()
+        };
+
+        let mut sources: Vec<Peekable<Box<dyn Iterator<Item = &String>>>> = segments
+            .iter()
+            .map(|segment| (Box::new(segment.keys()) as Box<dyn Iterator<Item = _>>).peekable())
+            .collect();
+        sources.push((Box::new(table_keys.iter()) as Box<dyn Iterator<Item = _>>).peekable());
+
+        // Repeatedly visit the smallest key any source has left, and skip past it in all of them
+        loop {
+            let Some(key) = sources.iter_mut().filter_map(|keys| keys.peek().copied()).min()
+            else {
+                return;
+            };
+            f(key);
+            for keys in sources.iter_mut() {
+                keys.next_if_eq(&key);
+            }
+        }
+    }
+
+    /// Measure the memory used by the postings held in memory and the size of the segments
+    pub fn stats(&self) -> IndexStats {
+        let layers = self.shared.layers.read().unwrap();
+
+        let mut memory = PostingStats::default();
+        for table in std::iter::once(&layers.memtable).chain(layers.frozen.iter()) {
+            memory.add(table.stats());
+        }
+
+        IndexStats {
+            memory,
+            segments: layers.segments.len(),
+            segment_bytes: layers.segments.iter().map(|segment| segment.size()).sum(),
+        }
+    }
+
+    /// Write every posting held in memory to segments, however few there are, and record them in
+    /// the manifest. Once this returns, the segments hold everything added to the index before it
+    /// was called. Does nothing for an index kept in memory.
+    pub fn flush(&self) -> io::Result<()> {
+        let Some(storage) = &self.shared.storage else {
+            return Ok(());
+        };
+        let _flushing = storage.flush_lock.lock().unwrap();
+        self.shared.freeze();
+        self.shared.write_frozen()
+    }
+
+    // Count a posting or tombstone added to the table, and flush the table once it is full.
+    fn added(&self) {
+        let Some(storage) = &self.shared.storage else {
+            return;
+        };
+
+        if storage.unflushed.fetch_add(1, Ordering::AcqRel) + 1 >= storage.flush_threshold {
+            self.shared.flush();
+            if self.shared.layers.read().unwrap().segments.len() >= MERGE_FACTOR {
+                self.start_merge();
+            }
+        }
+    }
+
+    // Merge segments on a background thread, unless one is already doing so.
+    fn start_merge(&self) {
+        let Some(storage) = &self.shared.storage else {
+            return;
+        };
+        if storage.merging.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let shared = Arc::clone(&self.shared);
+        let handle = thread::spawn(move || {
+            shared.merge();
+            if let Some(storage) = &shared.storage {
+                storage.merging.store(false, Ordering::Release);
+            }
+        });
+
+        // The previous thread has already finished, since `merging` was not set
+        if let Some(previous) = self.merger.lock().unwrap().replace(handle) {
+            let _ = previous.join();
+        }
+    }
+}
+
+impl Drop for InvertedIndex {
+    fn drop(&mut self) {
+        if let Some(merger) = self.merger.lock().unwrap().take() {
+            let _ = merger.join();
+        }
+    }
+}
+
+impl Shared {
+    // Freeze the table and write every frozen table to a segment, oldest first. A table that
+    // cannot be written stays frozen, and keeps serving lookups from memory until a later flush
+    // succeeds.
+    fn flush(&self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+        let Ok(_flushing) = storage.flush_lock.try_lock() else {
+            // Another thread is already flushing
+            return;
+        };
+        if storage.unflushed.load(Ordering::Acquire) < storage.flush_threshold {
+            return;
+        }
+
+        self.freeze();
+        if let Err(e) = self.write_frozen() {
+            eprintln!("Failed to flush the index: {}", e);
+        }
+    }
+
+    // Replace the table by an empty one, unless it is empty already, and queue it to be written.
+    fn freeze(&self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+        let mut layers = self.layers.write().unwrap();
+        if storage.unflushed.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let table = std::mem::replace(
+            &mut layers.memtable,
+            Arc::new(PostingTable::new(self.buckets)),
+        );
+        layers.frozen.push(table);
+        storage.unflushed.store(0, Ordering::Release);
+    }
+
+    // Write every frozen table to a segment, oldest first, recording each in the manifest. The
+    // caller holds the flush lock.
+    fn write_frozen(&self) -> io::Result<()> {
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+
+        loop {
+            let Some(table) = self.layers.read().unwrap().frozen.first().cloned() else {
+                return Ok(());
+            };
+
+            let mut lists = BTreeMap::new();
+            table.for_each(|key, list| {
+                lists.insert(key.clone(), list.clone());
+            });
+
+            let path = storage.next_path();
+            let segment = SegmentWriter::create(&path)
+                .and_then(|mut writer| {
+                    for (key, list) in lists.iter() {
+                        writer.add(key, &list.iter().collect::<Vec<_>>())?;
+                    }
+                    writer.finish()
+                })
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("failed to write segment {}: {}", path.display(), e),
+                    )
+                })?;
+
+            {
+                let mut layers = self.layers.write().unwrap();
+                layers.frozen.remove(0);
+                layers.segments.push(Arc::new(segment));
+            }
+            self.write_manifest()?;
+        }
+    }
+
+    // Write the list of segments in use to the manifest, replacing it atomically.
+    fn write_manifest(&self) -> io::Result<()> {
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+        let _writing = storage.manifest_lock.lock().unwrap();
+        let names: Vec<String> = self
+            .layers
+            .read()
+            .unwrap()
+            .segments
+            .iter()
+            .filter_map(|segment| Some(segment.path().file_name()?.to_str()?.to_string()))
+            .collect();
+
+        let path = storage.dir.join(MANIFEST_FILE);
+        let temporary = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        writer.write_all(MANIFEST_MAGIC)?;
+        writer.write_all(&MANIFEST_VERSION.to_be_bytes())?;
+        write_varint(&mut writer, names.len() as u64)?;
+        for name in names.iter() {
+            write_string(&mut writer, name)?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temporary, &path)
+    }
+
+    // Merge all segments into one for as long as there are at least `MERGE_FACTOR` of them.
+    fn merge(&self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+
+        loop {
+            let segments = self.layers.read().unwrap().segments.clone();
+            if segments.len() < MERGE_FACTOR {
+                return;
+            }
+
+            let path = storage.next_path();
+            let merged = match merge_segments(&segments, &path) {
+                Ok(merged) => merged,
+                Err(e) => {
+                    eprintln!("Failed to merge segments into {}: {}", path.display(), e);
+                    return;
+                }
+            };
+
+            // Flushes only append to the list, so the merged segments are still its oldest
+            {
+                let mut layers = self.layers.write().unwrap();
+                layers.segments.splice(..segments.len(), [Arc::new(merged)]);
+            }
+            // The merged segments are only removed once the manifest no longer lists them
+            if let Err(e) = self.write_manifest() {
+                eprintln!("Failed to write the index manifest: {}", e);
+                return;
+            }
+            for segment in segments.iter() {
+                segment.retire();
+            }
+        }
+    }
+}
+
+impl Storage {
+    fn next_path(&self) -> PathBuf {
+        let number = self.next_segment.fetch_add(1, Ordering::AcqRel);
+        self.dir
+            .join(format!("{:016}", number))
+            .with_extension(SEGMENT_EXTENSION)
+    }
+}
+
+// Merge `segments`, oldest first, into a new segment at `path`, one key at a time. The newest
+// posting of each document wins, and since no older segment is left for tombstones to hide
+// postings in, they are dropped.
+fn merge_segments(segments: &[Arc<Segment>], path: &Path) -> io::Result<Segment> {
+    let keys: BTreeSet<&String> = segments.iter().flat_map(|segment| segment.keys()).collect();
+
+    let mut writer = SegmentWriter::create(path)?;
+    for key in keys {
+        let mut newest: BTreeMap<usize, Posting> = BTreeMap::new();
+        for segment in segments.iter().rev() {
+            for posting in segment.get(key)? {
+                newest.entry(posting.doc).or_insert(posting);
+            }
+        }
+
+        let postings: Vec<Posting> = newest
+            .into_values()
+            .filter(|posting| !posting.positions.is_empty())
+            .collect();
+        if !postings.is_empty() {
+            writer.add(key, &postings)?;
+        }
+    }
+
+    writer.finish()
+}
+
+// Read the names of the segment files listed in the manifest at `path`
+fn read_manifest(path: &Path) -> io::Result<Vec<String>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    if &header[..8] != MANIFEST_MAGIC || header[8..] != MANIFEST_VERSION.to_be_bytes() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an index manifest",
+        ));
+    }
+
+    let count = read_varint(&mut reader)?;
+    (0..count).map(|_| read_string(&mut reader)).collect()
+}
+
+// The tombstone hiding older postings of `doc`
+fn tombstone(doc: usize) -> Posting {
+    Posting {
+        doc,
+        positions: Vec::new(),
+    }
+}
@@ -0,0 +1,82 @@
+use serde_json::Value;
+use std::fmt;
+
+// A JSON document is stored as published, but indexed by its string values. Each string is
+// indexed like a metadata field under the name of the key it belongs to, so a record like
+// `{"author": "Jane Austen"}` is found by `author:austen`. Nested objects are named by their path,
+// like `address.city`, and every string in an array is indexed under the array's name. Keys are
+// indexed as they are, so a key that is not made of letters, digits and underscores, like
+// `first-name`, is searched for by quoting it: `"first-name":jane`. The strings are also joined
+// into the text of the document, so plain searches find them too. Other values, like numbers and
+// booleans, are not indexed.
+
+/// The indexed content of a document published as a JSON object
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JsonDocument {
+    /// The string values of the document, one per line, with the keys of each object in sorted
+    /// order
+    pub text: String,
+    /// The name and value of every string in the document
+    pub fields: Vec<(String, String)>,
+}
+
+/// The reason a document could not be published as JSON
+#[derive(Debug)]
+pub enum JsonError {
+    /// The document is not valid JSON
+    Syntax(serde_json::Error),
+    /// The document is valid JSON, but not an object
+    NotAnObject,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::Syntax(e) => write!(f, "invalid JSON: {}", e),
+            JsonError::NotAnObject => write!(f, "the document is not a JSON object"),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl JsonDocument {
+    /// Parse `doc`, which must be a JSON object, and collect its string values
+    pub fn parse(doc: &str) -> Result<JsonDocument, JsonError> {
+        let value: Value = serde_json::from_str(doc).map_err(JsonError::Syntax)?;
+        if !value.is_object() {
+            return Err(JsonError::NotAnObject);
+        }
+
+        let mut document = JsonDocument::default();
+        document.collect("", &value);
+        Ok(document)
+    }
+
+    fn collect(&mut self, name: &str, value: &Value) {
+        match value {
+            Value::String(string) => {
+                if !self.text.is_empty() {
+                    self.text.push('\n');
+                }
+                self.text.push_str(string);
+                self.fields.push((name.to_string(), string.clone()));
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.collect(name, value);
+                }
+            }
+            Value::Object(object) => {
+                for (key, value) in object {
+                    if name.is_empty() {
+                        self.collect(key, value);
+                    } else {
+                        self.collect(&format!("{}.{}", name, key), value);
+                    }
+                }
+            }
+            Value::Null | Value::Bool(_) | Value::Number(_) => {}
+        }
+    }
+}
@@ -1,6 +1,20 @@
+pub mod checkpoint;
 pub mod client;
+pub mod content;
+pub mod counts;
 pub mod database;
+pub mod index;
+pub mod json;
 pub mod message;
+pub mod metadata;
 pub mod multimap;
 pub mod pool;
+pub mod posting;
+pub mod query;
+pub mod segment;
 pub mod server;
+pub mod snapshot;
+pub mod snippet;
+pub mod terms;
+pub mod tokenizer;
+pub mod wal;
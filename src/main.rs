@@ -1,6 +1,18 @@
 use clap::{Parser, Subcommand};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
 use ngram::client::Client;
+use ngram::database::DatabaseConfig;
+use ngram::message::{Response, SearchOptions};
+use ngram::metadata::Metadata;
 use ngram::server::Server;
+use ngram::tokenizer::{DefaultTokenizer, StemmingTokenizer, Tokenizer};
+
+/// The terminal escape code that starts highlighting a match in a snippet
+const HIGHLIGHT: &str = "\x1b[1;33m";
+/// The terminal escape code that ends highlighting
+const HIGHLIGHT_END: &str = "\x1b[0m";
 
 // TODO:
 // Fill out the `Args` struct to parse the command line arguments. You may find clap "subcommands"
@@ -23,20 +35,130 @@ enum Commands {
     },
     Server {
         listen_port: u16,
+        /// Index every run of up to this many consecutive words as an n-gram
+        #[arg(long, default_value_t = 1)]
+        max_ngram: usize,
+        /// A file of whitespace-separated words to leave out of the index, such as data/words.txt
+        #[arg(long)]
+        stop_words: Option<String>,
+        /// Reduce words to their English stems, so that a search for "running" also finds "runs"
+        #[arg(long)]
+        stem: bool,
+        /// Durably store documents in this directory, restoring them on the next start. Every change
+        /// is kept in a log that is never compacted, but each start only replays the changes
+        /// logged after the last checkpoint, and reuses the index segments written before it.
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        /// Start from the documents in a snapshot file written by the `snapshot` action
+        #[arg(long, conflicts_with = "data_dir")]
+        restore: Option<PathBuf>,
+        /// Let clients write snapshots with the `snapshot` action to files in this directory.
+        /// Snapshots are refused when it is not given.
+        #[arg(long)]
+        snapshot_dir: Option<PathBuf>,
+        /// With --data-dir, write the index to a new segment file every time this many postings
+        /// have been added. This bounds the memory the index uses while running.
+        #[arg(long, default_value_t = 1 << 20)]
+        flush_threshold: usize,
+        /// With --data-dir, write a checkpoint every time this many changes have been logged, so
+        /// that the next start only replays the changes made since. 0 disables checkpoints.
+        #[arg(long, default_value_t = 1000)]
+        checkpoint_interval: usize,
+        /// Answer the publish of a document that is already in the archive with the existing id
+        /// instead of adding a copy
+        #[arg(long)]
+        deduplicate: bool,
+        /// Expand a wildcard pattern like `mac*` to at most this many words
+        #[arg(long, default_value_t = 1000)]
+        max_expansions: usize,
     },
 }
 
 #[derive(Subcommand, Debug)]
 enum ClientActions {
+    /// Publish a document. Its title, author and year are read from a header line like
+    /// `[Emma by Jane Austen 1816]` unless given as options.
     Publish {
         document_path: String,
+        #[arg(long)]
+        title: Option<String>,
+        #[arg(long)]
+        author: Option<String>,
+        #[arg(long)]
+        year: Option<u32>,
+        /// A label to search for with `tag:...`. May be given more than once.
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Do not read metadata from the header line of the document
+        #[arg(long)]
+        no_header: bool,
+    },
+    /// Publish a JSON object, indexing each of its string values as a field named by its key
+    PublishJson {
+        document_path: String,
     },
+    /// Search for documents matching a query such as `hamlet AND (ghost OR "poor yorick")`
     Search {
-        word: String,
+        query: String,
+        /// Order the results from most to least relevant
+        #[arg(long)]
+        ranked: bool,
+        /// Show a snippet of each matching document with the matches highlighted
+        #[arg(long, conflicts_with = "ranked")]
+        snippets: bool,
+        /// Skip this many matching documents, in increasing order of id
+        #[arg(long, default_value_t = 0, conflicts_with = "ranked")]
+        offset: usize,
+        /// Show at most this many matching documents
+        #[arg(long, conflicts_with = "ranked")]
+        limit: Option<usize>,
+        /// Show how many words a `NEAR/n` operator spans in each matching document
+        #[arg(long, conflicts_with_all = ["ranked", "snippets"])]
+        spans: bool,
     },
     Retrieve {
         document_id: usize,
     },
+    Delete {
+        document_id: usize,
+    },
+    Update {
+        document_id: usize,
+        document_path: String,
+    },
+    /// Ask the server to write a snapshot of its archive to a file in its --snapshot-dir. The path
+    /// must be relative to that directory.
+    Snapshot {
+        snapshot_path: String,
+    },
+    /// Retrieve the title, author, year and tags of a document
+    Metadata {
+        document_id: usize,
+    },
+    /// Report the size of the server's index and the memory saved by compressing it
+    Stats,
+    /// Search for documents containing a word that matches a regular expression, like `colou?r`
+    SearchRegex {
+        pattern: String,
+    },
+    /// Search for documents containing words similar to `word`
+    FuzzySearch {
+        word: String,
+        /// The minimum similarity, between 0 and 1, of a matching word
+        #[arg(long, default_value_t = 0.5)]
+        threshold: f64,
+    },
+    /// Count how often a word or phrase occurs, in total and in each document
+    NgramStats {
+        ngram: String,
+    },
+    /// List the most frequent n-grams of a given number of words
+    TopNgrams {
+        n: usize,
+        /// The number of n-grams to list
+        #[arg(long, default_value_t = 10)]
+        k: usize,
+    },
 }
 // TODO:
 // Inspect the contents of the `args` struct that has been created from the command line arguments
@@ -55,18 +177,94 @@ fn main() {
             let client = Client::new(&server_address, server_port);
 
             match action {
-                ClientActions::Publish { document_path } => {
+                ClientActions::Publish {
+                    document_path,
+                    title,
+                    author,
+                    year,
+                    tags,
+                    no_header,
+                } => {
                     println!("Publishing document at: {}", document_path);
-                    match client.publish_from_path(&document_path) {
+                    let mut metadata = if no_header {
+                        Metadata::default()
+                    } else {
+                        std::fs::read_to_string(&document_path)
+                            .ok()
+                            .and_then(|doc| Metadata::from_header(&doc))
+                            .unwrap_or_default()
+                    };
+                    metadata.title = title.or(metadata.title);
+                    metadata.author = author.or(metadata.author);
+                    metadata.year = year.or(metadata.year);
+                    metadata.tags.extend(tags);
+
+                    let response = if metadata.is_empty() {
+                        client.publish_from_path(&document_path)
+                    } else {
+                        client.publish_with_metadata_from_path(&document_path, metadata)
+                    };
+                    match response {
                         Some(response) => println!("Response: {:?}", response),
                         None => eprintln!("Failed to publish document"),
                     }
                 }
-                ClientActions::Search { word } => {
-                    println!("Searching for word: {}", word);
-                    match client.search(&word) {
+                ClientActions::PublishJson { document_path } => {
+                    println!("Publishing JSON document at: {}", document_path);
+                    match client.publish_json_from_path(&document_path) {
                         Some(response) => println!("Response: {:?}", response),
-                        None => eprintln!("Failed to search for the word"),
+                        None => eprintln!("Failed to publish document"),
+                    }
+                }
+                ClientActions::Search {
+                    query,
+                    ranked,
+                    snippets,
+                    offset,
+                    limit,
+                    spans,
+                } => {
+                    println!("Searching for: {}", query);
+                    let response = if ranked {
+                        client.ranked_search(&query)
+                    } else {
+                        let options = SearchOptions {
+                            snippets,
+                            offset,
+                            limit,
+                            spans,
+                        };
+                        client.search_with_options(&query, options)
+                    };
+                    match response {
+                        Some(Response::SearchHits { total, hits }) => {
+                            println!("{}", page_summary(offset, hits.len(), total));
+                            for hit in hits {
+                                let snippet = hit.snippet.highlight(HIGHLIGHT, HIGHLIGHT_END);
+                                println!("[{}] {}", hit.id, snippet);
+                            }
+                        }
+                        Some(Response::SearchSpans { total, spans }) => {
+                            println!("{}", page_summary(offset, spans.len(), total));
+                            for (id, span) in spans {
+                                match span {
+                                    Some(span) => println!("[{}] within {} words", id, span),
+                                    None => println!("[{}]", id),
+                                }
+                            }
+                        }
+                        Some(Response::SearchSuggestions(suggestions)) => {
+                            println!("No documents found. Did you mean:");
+                            for suggestion in suggestions {
+                                println!("    {}", suggestion);
+                            }
+                        }
+                        Some(Response::SearchPage { total, ids }) => {
+                            println!("{}", page_summary(offset, ids.len(), total));
+                            println!("Response: {:?}", ids);
+                        }
+                        Some(response) => println!("Response: {:?}", response),
+                        None => eprintln!("Failed to search for the query"),
                     }
                 }
                 ClientActions::Retrieve { document_id } => {
@@ -76,13 +274,185 @@ fn main() {
                         None => eprintln!("Failed to retrieve document"),
                     }
                 }
+                ClientActions::Delete { document_id } => {
+                    println!("Deleting document with ID: {}", document_id);
+                    match client.delete(document_id) {
+                        Some(response) => println!("Response: {:?}", response),
+                        None => eprintln!("Failed to delete document"),
+                    }
+                }
+                ClientActions::Update {
+                    document_id,
+                    document_path,
+                } => {
+                    println!(
+                        "Updating document with ID {} from: {}",
+                        document_id, document_path
+                    );
+                    match client.update_from_path(document_id, &document_path) {
+                        Some(response) => println!("Response: {:?}", response),
+                        None => eprintln!("Failed to update document"),
+                    }
+                }
+                ClientActions::Snapshot { snapshot_path } => {
+                    println!("Writing snapshot to: {}", snapshot_path);
+                    match client.snapshot(&snapshot_path) {
+                        Some(response) => println!("Response: {:?}", response),
+                        None => eprintln!("Failed to write snapshot"),
+                    }
+                }
+                ClientActions::Metadata { document_id } => {
+                    println!("Retrieving metadata of document with ID: {}", document_id);
+                    match client.retrieve_metadata(document_id) {
+                        Some(response) => println!("Response: {:?}", response),
+                        None => eprintln!("Failed to retrieve metadata"),
+                    }
+                }
+                ClientActions::Stats => {
+                    println!("Requesting index statistics");
+                    match client.index_stats() {
+                        Some(response) => println!("Response: {:?}", response),
+                        None => eprintln!("Failed to get index statistics"),
+                    }
+                }
+                ClientActions::SearchRegex { pattern } => {
+                    println!("Searching for words matching: {}", pattern);
+                    match client.search_regex(&pattern) {
+                        Some(Response::RegexSearchSuccess {
+                            ids,
+                            terms,
+                            truncated,
+                        }) => {
+                            println!("Matched {} words", terms);
+                            if truncated {
+                                println!(
+                                    "More words matched than the server expands a pattern to, \
+                                     so some documents may be missing"
+                                );
+                            }
+                            println!("Response: {:?}", ids);
+                        }
+                        Some(response) => println!("Response: {:?}", response),
+                        None => eprintln!("Failed to search for the pattern"),
+                    }
+                }
+                ClientActions::FuzzySearch { word, threshold } => {
+                    println!("Fuzzy searching for word: {}", word);
+                    match client.fuzzy_search(&word, threshold) {
+                        Some(response) => println!("Response: {:?}", response),
+                        None => eprintln!("Failed to search for the word"),
+                    }
+                }
+                ClientActions::NgramStats { ngram } => {
+                    println!("Counting occurrences of: {}", ngram);
+                    match client.ngram_stats(&ngram) {
+                        Some(Response::NgramStatsSuccess(stats)) => {
+                            println!(
+                                "{} occurrences in {} documents",
+                                stats.total, stats.documents
+                            );
+                            for (id, count) in stats.counts {
+                                println!("  document {}: {}", id, count);
+                            }
+                        }
+                        Some(response) => println!("Response: {:?}", response),
+                        None => eprintln!("Failed to count the n-gram"),
+                    }
+                }
+                ClientActions::TopNgrams { n, k } => {
+                    println!("Finding the {} most frequent {}-grams", k, n);
+                    match client.top_ngrams(n, k) {
+                        Some(Response::TopNgramsSuccess(ngrams)) => {
+                            for (ngram, count) in ngrams {
+                                println!("{:>8}  {}", count, ngram);
+                            }
+                        }
+                        Some(response) => println!("Response: {:?}", response),
+                        None => eprintln!("Failed to find the most frequent n-grams"),
+                    }
+                }
             }
         }
-        Commands::Server { listen_port } => {
+        Commands::Server {
+            listen_port,
+            max_ngram,
+            stop_words,
+            stem,
+            data_dir,
+            restore,
+            snapshot_dir,
+            flush_threshold,
+            checkpoint_interval,
+            deduplicate,
+            max_expansions,
+        } => {
+            let stop_words = match stop_words {
+                Some(path) => match std::fs::read_to_string(&path) {
+                    Ok(words) => words.split_whitespace().map(str::to_string).collect(),
+                    Err(e) => {
+                        eprintln!("Failed to read stop words from {}: {}", path, e);
+                        return;
+                    }
+                },
+                None => HashSet::new(),
+            };
+
+            let tokenizer: Arc<dyn Tokenizer> = if stem {
+                Arc::new(StemmingTokenizer::new(DefaultTokenizer))
+            } else {
+                Arc::new(DefaultTokenizer)
+            };
+
             println!("Starting server on port: {}", listen_port);
-            let server = Server::new();  
+            let config = DatabaseConfig {
+                max_ngram,
+                tokenizer,
+                stop_words,
+                flush_threshold,
+                deduplicate,
+                max_expansions,
+                checkpoint_interval,
+            };
+            let server = if let Some(data_dir) = data_dir {
+                match Server::open(config, &data_dir) {
+                    Ok(server) => server,
+                    Err(e) => {
+                        eprintln!("Failed to open data directory {}: {}", data_dir.display(), e);
+                        return;
+                    }
+                }
+            } else if let Some(snapshot) = restore {
+                match Server::restore(config, &snapshot) {
+                    Ok(server) => server,
+                    Err(e) => {
+                        eprintln!("Failed to restore snapshot {}: {}", snapshot.display(), e);
+                        return;
+                    }
+                }
+            } else {
+                Server::with_config(config)
+            };
+            let server = match snapshot_dir {
+                Some(dir) => server.with_snapshot_dir(dir),
+                None => server,
+            };
 
             server.run(listen_port);
         }
     }
 }
+
+// Describe which of the `total` matching documents a page of `len` documents starting at `offset`
+// shows, like "Showing documents 11 to 20 of 42".
+fn page_summary(offset: usize, len: usize, total: usize) -> String {
+    if len == 0 {
+        format!("No documents to show of {}", total)
+    } else {
+        format!(
+            "Showing documents {} to {} of {}",
+            offset + 1,
+            offset + len,
+            total
+        )
+    }
+}
@@ -1,12 +1,50 @@
+use crate::counts::NgramStats;
+use crate::index::IndexStats;
+use crate::metadata::Metadata;
+use crate::posting::PostingStats;
+use crate::snippet::{SearchHit, Snippet};
+use std::io::Read;
+
 /// A request from the client to the server
 #[derive(Debug, PartialEq)]
 pub enum Request {
     /// Add the document `doc` to the archive
     Publish { doc: String },
-    /// Search for the word `word` in the archive
-    Search { word: String },
+    /// Search for the documents matching the query `word`. A query is a word, a phrase whose
+    /// words must appear consecutively, or a combination of those using `AND`, `OR`, `NOT` and
+    /// parentheses. `options` controls what the response includes
+    Search { word: String, options: SearchOptions },
     /// Retrieve the document with the index `id` from the archive
     Retrieve { id: usize },
+    /// Search for documents containing words similar to `word`, such as misspellings or words of
+    /// which `word` is a part. `threshold` is the minimum similarity, between 0 and 1, of a
+    /// matching word
+    FuzzySearch { word: String, threshold: f64 },
+    /// Search for the documents matching the query `query` like `Search`, ordered from most to
+    /// least relevant
+    RankedSearch { query: String },
+    /// Delete the document with the index `id` from the archive
+    Delete { id: usize },
+    /// Replace the document with the index `id` by `doc`, keeping its index
+    Update { id: usize, doc: String },
+    /// Write a snapshot of the whole archive to the file at `path` inside the server's
+    /// snapshot directory
+    Snapshot { path: String },
+    /// Report the size of the reverse index
+    IndexStats,
+    /// Add the document `doc` to the archive, described by `metadata`
+    PublishWithMetadata { doc: String, metadata: Metadata },
+    /// Retrieve the metadata of the document with the index `id`
+    RetrieveMetadata { id: usize },
+    /// Add the document `doc`, a JSON object whose string values are indexed as fields named by
+    /// their keys, to the archive
+    PublishJson { doc: String },
+    /// Search for the documents containing a word that matches the regular expression `pattern`
+    SearchRegex { pattern: String },
+    /// Count the occurrences of the phrase `ngram` in every document
+    NgramStats { ngram: String },
+    /// Find the `k` most frequent n-grams of `n` words across all documents
+    TopNgrams { n: usize, k: usize },
 }
 impl Request {
     // TODO:
@@ -19,20 +57,89 @@ impl Request {
         match self {
             Request::Publish { doc} => {
                 bytes.push(0x01);
-                bytes.extend((doc.len() as usize).to_be_bytes());
+                bytes.extend(doc.len().to_be_bytes());
                 bytes.extend(doc.as_bytes());
 
             },
-            Request::Search { word } => {
+            Request::Search { word, options } => {
                 bytes.push(0x02);
-                bytes.extend((word.len() as usize).to_be_bytes());
+                bytes.extend(word.len().to_be_bytes());
                 bytes.extend(word.as_bytes());
+                bytes.push(options.snippets as u8);
+                bytes.extend(options.offset.to_be_bytes());
+                match options.limit {
+                    Some(limit) => {
+                        bytes.push(1);
+                        bytes.extend(limit.to_be_bytes());
+                    }
+                    None => bytes.push(0),
+                }
+                bytes.push(options.spans as u8);
 
             },
             Request::Retrieve { id } => {
                 bytes.push(0x03);
                 bytes.extend(id.to_be_bytes());
             }
+            Request::FuzzySearch { word, threshold } => {
+                bytes.push(0x04);
+                bytes.extend(word.len().to_be_bytes());
+                bytes.extend(word.as_bytes());
+                bytes.extend(threshold.to_be_bytes());
+            }
+            Request::RankedSearch { query } => {
+                bytes.push(0x05);
+                bytes.extend(query.len().to_be_bytes());
+                bytes.extend(query.as_bytes());
+            }
+            Request::Delete { id } => {
+                bytes.push(0x06);
+                bytes.extend(id.to_be_bytes());
+            }
+            Request::Update { id, doc } => {
+                bytes.push(0x07);
+                bytes.extend(id.to_be_bytes());
+                bytes.extend(doc.len().to_be_bytes());
+                bytes.extend(doc.as_bytes());
+            }
+            Request::Snapshot { path } => {
+                bytes.push(0x08);
+                bytes.extend(path.len().to_be_bytes());
+                bytes.extend(path.as_bytes());
+            }
+            Request::IndexStats => {
+                bytes.push(0x09);
+            }
+            Request::PublishWithMetadata { doc, metadata } => {
+                bytes.push(0x0A);
+                bytes.extend(doc.len().to_be_bytes());
+                bytes.extend(doc.as_bytes());
+                write_metadata(&mut bytes, metadata);
+            }
+            Request::RetrieveMetadata { id } => {
+                bytes.push(0x0B);
+                bytes.extend(id.to_be_bytes());
+            }
+            Request::PublishJson { doc } => {
+                bytes.push(0x0C);
+                bytes.extend(doc.len().to_be_bytes());
+                bytes.extend(doc.as_bytes());
+            }
+            Request::SearchRegex { pattern } => {
+                bytes.push(0x0D);
+                bytes.extend(pattern.len().to_be_bytes());
+                bytes.extend(pattern.as_bytes());
+            }
+            Request::NgramStats { ngram } => {
+                bytes.push(0x0E);
+                bytes.extend(ngram.len().to_be_bytes());
+                bytes.extend(ngram.as_bytes());
+            }
+            Request::TopNgrams { n, k } => {
+                bytes.push(0x0F);
+                bytes.extend(n.to_be_bytes());
+                bytes.extend(k.to_be_bytes());
+            }
         }
 
         bytes
@@ -63,17 +170,22 @@ impl Request {
                 
             },
             0x02 => {
-                reader.read_exact(&mut len_buffer).unwrap();
+                let word = read_string(&mut reader)?;
 
-                let len = usize::from_be_bytes(len_buffer);
+                let snippets = read_flag(&mut reader)?;
 
-                let mut word_buffer = vec![0;len];
+                let offset = read_usize(&mut reader)?;
 
-                reader.read_exact(&mut word_buffer).unwrap();
+                let limit = match read_flag(&mut reader)? {
+                    true => Some(read_usize(&mut reader)?),
+                    false => None,
+                };
 
-                let word = String::from_utf8(word_buffer).unwrap();
+                let spans = read_flag(&mut reader)?;
 
-                Some(Request::Search {word})
+                let options = SearchOptions { snippets, offset, limit, spans };
+
+                Some(Request::Search {word, options})
                 
             },
             0x03 => {
@@ -84,6 +196,82 @@ impl Request {
                 Some(Request::Retrieve {id})
                 
             },
+            0x04 => {
+                let word = read_string(&mut reader)?;
+
+                let mut threshold_buffer = [0; 8];
+
+                reader.read_exact(&mut threshold_buffer).ok()?;
+
+                let threshold = f64::from_be_bytes(threshold_buffer);
+
+                Some(Request::FuzzySearch { word, threshold })
+            },
+            0x05 => {
+                let query = read_string(&mut reader)?;
+
+                Some(Request::RankedSearch { query })
+            },
+            0x06 => {
+                reader.read_exact(&mut len_buffer).ok()?;
+
+                let id = usize::from_be_bytes(len_buffer);
+
+                Some(Request::Delete { id })
+            },
+            0x07 => {
+                let mut id_buffer = [0; 8];
+
+                reader.read_exact(&mut id_buffer).ok()?;
+
+                let id = usize::from_be_bytes(id_buffer);
+
+                let doc = read_string(&mut reader)?;
+
+                Some(Request::Update { id, doc })
+            },
+            0x08 => {
+                let path = read_string(&mut reader)?;
+
+                Some(Request::Snapshot { path })
+            },
+            0x09 => Some(Request::IndexStats),
+            0x0A => {
+                let doc = read_string(&mut reader)?;
+
+                let metadata = read_metadata(&mut reader)?;
+
+                Some(Request::PublishWithMetadata { doc, metadata })
+            },
+            0x0B => {
+                reader.read_exact(&mut len_buffer).ok()?;
+
+                let id = usize::from_be_bytes(len_buffer);
+
+                Some(Request::RetrieveMetadata { id })
+            },
+            0x0C => {
+                let doc = read_string(&mut reader)?;
+
+                Some(Request::PublishJson { doc })
+            },
+            0x0D => {
+                let pattern = read_string(&mut reader)?;
+
+                Some(Request::SearchRegex { pattern })
+            },
+            0x0E => {
+                let ngram = read_string(&mut reader)?;
+
+                Some(Request::NgramStats { ngram })
+            },
+            0x0F => {
+                let n = read_usize(&mut reader)?;
+
+                let k = read_usize(&mut reader)?;
+
+                Some(Request::TopNgrams { n, k })
+            },
             _ => None,
         }
 
@@ -91,18 +279,94 @@ impl Request {
     }
 }
 
+/// Options for a `Search` request
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SearchOptions {
+    /// Answer with a snippet of each matching document around its first match, as
+    /// `SearchHits`, instead of only the ids of the documents
+    pub snippets: bool,
+    /// The number of matching documents to skip, in increasing order of index
+    pub offset: usize,
+    /// The largest number of matching documents to return
+    pub limit: Option<usize>,
+    /// Answer with the smallest number of words spanned by a `NEAR/n` operator in each matching
+    /// document, as `SearchSpans`. Ignored if `snippets` is set.
+    pub spans: bool,
+}
+
+impl SearchOptions {
+    /// Whether the options ask for only some of the matching documents, which are answered
+    /// together with the total number of matches
+    pub fn is_paged(&self) -> bool {
+        self.offset > 0 || self.limit.is_some()
+    }
+}
+
 /// A response from the server to the client
 #[derive(Debug, PartialEq)]
 pub enum Response {
-    /// The document was successfully added to the archive with the given index
-    PublishSuccess(usize),
+    /// The document was successfully added to the archive with the given index. The flag is set
+    /// if the archive already held a document with the same content, in which case nothing was
+    /// added and the index is that of the existing document
+    PublishSuccess(usize, bool),
     /// The search for the word was successful, and the indices of the documents containing the
-    /// word are returned
+    /// word (or phrase) are returned
     SearchSuccess(Vec<usize>),
     /// The retrieval of the document was successful, and the document is returned
     RetrieveSuccess(String),
     /// The request failed
     Failure,
+    /// The search query could not be parsed, for the given reason
+    InvalidQuery(String),
+    /// The ranked search was successful, and the indices of the matching documents are returned
+    /// with their relevance scores, most relevant first
+    RankedSearchSuccess(Vec<(usize, f64)>),
+    /// The search was rejected because the given phrase consists only of stop words, which are
+    /// too common to be indexed
+    StopWord(String),
+    /// The document was successfully deleted from the archive
+    DeleteSuccess,
+    /// The document was successfully replaced
+    UpdateSuccess,
+    /// The snapshot was successfully written
+    SnapshotSuccess,
+    /// The size of the reverse index
+    IndexStatsSuccess(IndexStats),
+    /// The retrieval of the metadata was successful, and the metadata is returned
+    MetadataSuccess(Metadata),
+    /// The document could not be published, for the given reason
+    InvalidDocument(String),
+    /// The search was successful, and the requested page of the matching documents is returned
+    /// in increasing order of index, each with a snippet of its text, together with the total
+    /// number of matching documents
+    SearchHits { total: usize, hits: Vec<SearchHit> },
+    /// The search was successful, and the indices of the requested page of the matching
+    /// documents are returned in increasing order, together with the total number of matching
+    /// documents
+    SearchPage { total: usize, ids: Vec<usize> },
+    /// The regular expression search was successful, and the indices of the matching documents
+    /// are returned in increasing order, together with the number of matching words they were
+    /// looked up by and whether more words matched than the server's expansion limit
+    RegexSearchSuccess {
+        ids: Vec<usize>,
+        terms: usize,
+        truncated: bool,
+    },
+    /// The search was successful, and the indices of the requested page of the matching
+    /// documents are returned in increasing order, each with the smallest number of words a
+    /// `NEAR/n` operator spans in it, together with the total number of matching documents
+    SearchSpans {
+        total: usize,
+        spans: Vec<(usize, Option<usize>)>,
+    },
+    /// The search found no documents, and these indexed words, which are spelled like words of
+    /// the query that occur in no document, are suggested instead, best first
+    SearchSuggestions(Vec<String>),
+    /// The number of occurrences of the n-gram in every document
+    NgramStatsSuccess(NgramStats),
+    /// The most frequent n-grams are returned with their numbers of occurrences, most frequent
+    /// first
+    TopNgramsSuccess(Vec<(String, usize)>),
 }
 impl Response {
     // TODO:
@@ -113,16 +377,18 @@ impl Response {
 
         // Use hex 0x01, 0x02, 0x03 to distinguish between different request
         match self {
-            Response::PublishSuccess (index) => {
+            Response::PublishSuccess (index, deduplicated) => {
                 bytes.push(0x01);
 
                 bytes.extend(index.to_be_bytes());
 
+                bytes.push(*deduplicated as u8);
+
             },
             Response::SearchSuccess(indices) => {
                 bytes.push(0x02);
 
-                bytes.extend((indices.len() as usize).to_be_bytes());
+                bytes.extend(indices.len().to_be_bytes());
 
                 for &index in indices {
                     bytes.extend(index.to_be_bytes());
@@ -132,13 +398,163 @@ impl Response {
             Response::RetrieveSuccess(doc) => {
                 bytes.push(0x03);
 
-                bytes.extend((doc.len() as usize).to_be_bytes());
+                bytes.extend(doc.len().to_be_bytes());
 
                 bytes.extend(doc.as_bytes());
             }
             Response::Failure => {
                 bytes.push(0x04);
             }
+            Response::InvalidQuery(reason) => {
+                bytes.push(0x05);
+
+                bytes.extend(reason.len().to_be_bytes());
+
+                bytes.extend(reason.as_bytes());
+            }
+            Response::RankedSearchSuccess(results) => {
+                bytes.push(0x06);
+
+                bytes.extend(results.len().to_be_bytes());
+
+                for (index, score) in results {
+                    bytes.extend(index.to_be_bytes());
+                    bytes.extend(score.to_be_bytes());
+                }
+            }
+            Response::StopWord(phrase) => {
+                bytes.push(0x07);
+
+                bytes.extend(phrase.len().to_be_bytes());
+
+                bytes.extend(phrase.as_bytes());
+            }
+            Response::DeleteSuccess => {
+                bytes.push(0x08);
+            }
+            Response::UpdateSuccess => {
+                bytes.push(0x09);
+            }
+            Response::SnapshotSuccess => {
+                bytes.push(0x0A);
+            }
+            Response::IndexStatsSuccess(stats) => {
+                bytes.push(0x0B);
+
+                bytes.extend(stats.memory.keys.to_be_bytes());
+                bytes.extend(stats.memory.postings.to_be_bytes());
+                bytes.extend(stats.memory.compressed_bytes.to_be_bytes());
+                bytes.extend(stats.memory.uncompressed_bytes.to_be_bytes());
+                bytes.extend(stats.segments.to_be_bytes());
+                bytes.extend(stats.segment_bytes.to_be_bytes());
+            }
+            Response::MetadataSuccess(metadata) => {
+                bytes.push(0x0C);
+
+                write_metadata(&mut bytes, metadata);
+            }
+            Response::InvalidDocument(reason) => {
+                bytes.push(0x0D);
+
+                bytes.extend(reason.len().to_be_bytes());
+
+                bytes.extend(reason.as_bytes());
+            }
+            Response::SearchHits { total, hits } => {
+                bytes.push(0x0E);
+
+                bytes.extend(total.to_be_bytes());
+
+                bytes.extend(hits.len().to_be_bytes());
+
+                for hit in hits {
+                    bytes.extend(hit.id.to_be_bytes());
+                    bytes.extend(hit.snippet.text.len().to_be_bytes());
+                    bytes.extend(hit.snippet.text.as_bytes());
+                    bytes.extend(hit.snippet.matches.len().to_be_bytes());
+                    for (start, end) in hit.snippet.matches.iter() {
+                        bytes.extend(start.to_be_bytes());
+                        bytes.extend(end.to_be_bytes());
+                    }
+                }
+            }
+            Response::SearchPage { total, ids } => {
+                bytes.push(0x0F);
+
+                bytes.extend(total.to_be_bytes());
+
+                bytes.extend(ids.len().to_be_bytes());
+
+                for id in ids {
+                    bytes.extend(id.to_be_bytes());
+                }
+            }
+            Response::RegexSearchSuccess { ids, terms, truncated } => {
+                bytes.push(0x10);
+
+                bytes.extend(ids.len().to_be_bytes());
+
+                for id in ids {
+                    bytes.extend(id.to_be_bytes());
+                }
+
+                bytes.extend(terms.to_be_bytes());
+
+                bytes.push(*truncated as u8);
+            }
+            Response::SearchSpans { total, spans } => {
+                bytes.push(0x11);
+
+                bytes.extend(total.to_be_bytes());
+
+                bytes.extend(spans.len().to_be_bytes());
+
+                for (id, span) in spans {
+                    bytes.extend(id.to_be_bytes());
+                    match span {
+                        Some(span) => {
+                            bytes.push(1);
+                            bytes.extend(span.to_be_bytes());
+                        }
+                        None => bytes.push(0),
+                    }
+                }
+            }
+            Response::SearchSuggestions(suggestions) => {
+                bytes.push(0x12);
+
+                bytes.extend(suggestions.len().to_be_bytes());
+
+                for suggestion in suggestions {
+                    bytes.extend(suggestion.len().to_be_bytes());
+                    bytes.extend(suggestion.as_bytes());
+                }
+            }
+            Response::NgramStatsSuccess(stats) => {
+                bytes.push(0x13);
+
+                bytes.extend(stats.total.to_be_bytes());
+
+                bytes.extend(stats.documents.to_be_bytes());
+
+                bytes.extend(stats.counts.len().to_be_bytes());
+
+                for (id, count) in stats.counts.iter() {
+                    bytes.extend(id.to_be_bytes());
+                    bytes.extend(count.to_be_bytes());
+                }
+            }
+            Response::TopNgramsSuccess(ngrams) => {
+                bytes.push(0x14);
+
+                bytes.extend(ngrams.len().to_be_bytes());
+
+                for (ngram, count) in ngrams {
+                    bytes.extend(ngram.len().to_be_bytes());
+                    bytes.extend(ngram.as_bytes());
+                    bytes.extend(count.to_be_bytes());
+                }
+            }
         }
 
         bytes
@@ -158,7 +574,9 @@ impl Response {
 
                 let index = usize::from_be_bytes(index_buffer);
 
-                Some(Response::PublishSuccess(index))
+                let deduplicated = read_flag(&mut reader)?;
+
+                Some(Response::PublishSuccess(index, deduplicated))
             },
             0x02 => {
                 let mut len_buffer = [0; 8];
@@ -195,6 +613,229 @@ impl Response {
 
             0x04 => Some(Response::Failure), 
 
+            0x05 => {
+                let mut len_buffer = [0; 8];
+
+                reader.read_exact(&mut len_buffer).ok()?;
+
+                let len = usize::from_be_bytes(len_buffer);
+
+                let mut reason_buffer = vec![0; len];
+
+                reader.read_exact(&mut reason_buffer).ok()?;
+
+                let reason = String::from_utf8(reason_buffer).ok()?;
+
+                Some(Response::InvalidQuery(reason))
+            },
+
+            0x06 => {
+                let mut len_buffer = [0; 8];
+
+                reader.read_exact(&mut len_buffer).ok()?;
+
+                let len = usize::from_be_bytes(len_buffer);
+
+                let mut results = Vec::new();
+
+                for _ in 0..len {
+                    let mut index_buffer = [0; 8];
+                    reader.read_exact(&mut index_buffer).ok()?;
+                    let mut score_buffer = [0; 8];
+                    reader.read_exact(&mut score_buffer).ok()?;
+                    let index = usize::from_be_bytes(index_buffer);
+                    let score = f64::from_be_bytes(score_buffer);
+                    results.push((index, score));
+                }
+
+                Some(Response::RankedSearchSuccess(results))
+            },
+
+            0x07 => {
+                let mut len_buffer = [0; 8];
+
+                reader.read_exact(&mut len_buffer).ok()?;
+
+                let len = usize::from_be_bytes(len_buffer);
+
+                let mut phrase_buffer = vec![0; len];
+
+                reader.read_exact(&mut phrase_buffer).ok()?;
+
+                let phrase = String::from_utf8(phrase_buffer).ok()?;
+
+                Some(Response::StopWord(phrase))
+            },
+
+            0x08 => Some(Response::DeleteSuccess),
+
+            0x09 => Some(Response::UpdateSuccess),
+
+            0x0A => Some(Response::SnapshotSuccess),
+
+            0x0B => {
+                let mut fields = [0; 6];
+
+                for field in fields.iter_mut() {
+                    let mut field_buffer = [0; 8];
+                    reader.read_exact(&mut field_buffer).ok()?;
+                    *field = u64::from_be_bytes(field_buffer);
+                }
+
+                let [keys, postings, compressed_bytes, uncompressed_bytes, segments, segment_bytes] =
+                    fields;
+
+                Some(Response::IndexStatsSuccess(IndexStats {
+                    memory: PostingStats {
+                        keys: usize::try_from(keys).ok()?,
+                        postings: usize::try_from(postings).ok()?,
+                        compressed_bytes: usize::try_from(compressed_bytes).ok()?,
+                        uncompressed_bytes: usize::try_from(uncompressed_bytes).ok()?,
+                    },
+                    segments: usize::try_from(segments).ok()?,
+                    segment_bytes,
+                }))
+            },
+
+            0x0C => {
+                let metadata = read_metadata(&mut reader)?;
+
+                Some(Response::MetadataSuccess(metadata))
+            },
+
+            0x0D => {
+                let reason = read_string(&mut reader)?;
+
+                Some(Response::InvalidDocument(reason))
+            },
+
+            0x0E => {
+                let total = read_usize(&mut reader)?;
+
+                let len = read_usize(&mut reader)?;
+
+                let mut hits = Vec::new();
+
+                for _ in 0..len {
+                    let id = read_usize(&mut reader)?;
+                    let text = read_string(&mut reader)?;
+
+                    let mut matches = Vec::new();
+                    for _ in 0..read_usize(&mut reader)? {
+                        let start = read_usize(&mut reader)?;
+                        let end = read_usize(&mut reader)?;
+                        // Matches must be slices of the text
+                        let valid = start <= end
+                            && text.is_char_boundary(start)
+                            && text.is_char_boundary(end);
+                        if !valid {
+                            return None;
+                        }
+                        matches.push((start, end));
+                    }
+
+                    hits.push(SearchHit {
+                        id,
+                        snippet: Snippet { text, matches },
+                    });
+                }
+
+                Some(Response::SearchHits { total, hits })
+            },
+
+            0x0F => {
+                let total = read_usize(&mut reader)?;
+
+                let len = read_usize(&mut reader)?;
+
+                let mut ids = Vec::new();
+
+                for _ in 0..len {
+                    ids.push(read_usize(&mut reader)?);
+                }
+
+                Some(Response::SearchPage { total, ids })
+            },
+
+            0x10 => {
+                let len = read_usize(&mut reader)?;
+
+                let mut ids = Vec::new();
+
+                for _ in 0..len {
+                    ids.push(read_usize(&mut reader)?);
+                }
+
+                let terms = read_usize(&mut reader)?;
+
+                let truncated = read_flag(&mut reader)?;
+
+                Some(Response::RegexSearchSuccess { ids, terms, truncated })
+            },
+
+            0x11 => {
+                let total = read_usize(&mut reader)?;
+
+                let len = read_usize(&mut reader)?;
+
+                let mut spans = Vec::new();
+
+                for _ in 0..len {
+                    let id = read_usize(&mut reader)?;
+                    let span = match read_flag(&mut reader)? {
+                        true => Some(read_usize(&mut reader)?),
+                        false => None,
+                    };
+                    spans.push((id, span));
+                }
+
+                Some(Response::SearchSpans { total, spans })
+            },
+
+            0x12 => {
+                let len = read_usize(&mut reader)?;
+
+                let mut suggestions = Vec::new();
+
+                for _ in 0..len {
+                    suggestions.push(read_string(&mut reader)?);
+                }
+
+                Some(Response::SearchSuggestions(suggestions))
+            },
+
+            0x13 => {
+                let total = read_usize(&mut reader)?;
+
+                let documents = read_usize(&mut reader)?;
+
+                let len = read_usize(&mut reader)?;
+
+                let mut counts = Vec::new();
+
+                for _ in 0..len {
+                    let id = read_usize(&mut reader)?;
+                    let count = read_usize(&mut reader)?;
+                    counts.push((id, count));
+                }
+
+                Some(Response::NgramStatsSuccess(NgramStats { total, documents, counts }))
+            },
+
+            0x14 => {
+                let len = read_usize(&mut reader)?;
+
+                let mut ngrams = Vec::new();
+
+                for _ in 0..len {
+                    let ngram = read_string(&mut reader)?;
+                    let count = read_usize(&mut reader)?;
+                    ngrams.push((ngram, count));
+                }
+
+                Some(Response::TopNgramsSuccess(ngrams))
+            },
+
             _ => None, 
         }
 
@@ -203,3 +844,86 @@ impl Response {
     }
 }
 
+// Metadata is encoded as its title and author, each a presence byte followed by the length and
+// bytes of the string if present, its year as a presence byte followed by the year as a u32 if
+// present, and then the number of tags followed by the length and bytes of each tag.
+fn write_metadata(bytes: &mut Vec<u8>, metadata: &Metadata) {
+    for field in [&metadata.title, &metadata.author] {
+        match field {
+            Some(value) => {
+                bytes.push(1);
+                bytes.extend(value.len().to_be_bytes());
+                bytes.extend(value.as_bytes());
+            }
+            None => bytes.push(0),
+        }
+    }
+
+    match metadata.year {
+        Some(year) => {
+            bytes.push(1);
+            bytes.extend(year.to_be_bytes());
+        }
+        None => bytes.push(0),
+    }
+
+    bytes.extend(metadata.tags.len().to_be_bytes());
+    for tag in metadata.tags.iter() {
+        bytes.extend(tag.len().to_be_bytes());
+        bytes.extend(tag.as_bytes());
+    }
+}
+
+fn read_metadata<R: Read>(reader: &mut R) -> Option<Metadata> {
+    let mut metadata = Metadata::default();
+
+    for field in [&mut metadata.title, &mut metadata.author] {
+        if read_flag(reader)? {
+            *field = Some(read_string(reader)?);
+        }
+    }
+
+    if read_flag(reader)? {
+        let mut year_buffer = [0; 4];
+        reader.read_exact(&mut year_buffer).ok()?;
+        metadata.year = Some(u32::from_be_bytes(year_buffer));
+    }
+
+    let mut len_buffer = [0; 8];
+    reader.read_exact(&mut len_buffer).ok()?;
+    let len = usize::from_be_bytes(len_buffer);
+    for _ in 0..len {
+        metadata.tags.push(read_string(reader)?);
+    }
+
+    Some(metadata)
+}
+
+fn read_usize<R: Read>(reader: &mut R) -> Option<usize> {
+    let mut usize_buffer = [0; 8];
+    reader.read_exact(&mut usize_buffer).ok()?;
+    Some(usize::from_be_bytes(usize_buffer))
+}
+
+fn read_flag<R: Read>(reader: &mut R) -> Option<bool> {
+    let mut flag_buffer = [0; 1];
+    reader.read_exact(&mut flag_buffer).ok()?;
+    match flag_buffer[0] {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Option<String> {
+    let mut len_buffer = [0; 8];
+    reader.read_exact(&mut len_buffer).ok()?;
+    let len = usize::from_be_bytes(len_buffer);
+
+    let mut string_buffer = Vec::new();
+    reader.take(len as u64).read_to_end(&mut string_buffer).ok()?;
+    if string_buffer.len() != len {
+        return None;
+    }
+    String::from_utf8(string_buffer).ok()
+}
@@ -0,0 +1,69 @@
+// Metadata describes a document with a few named fields. Each field is indexed separately from
+// the text of the document, so that a query like `author:austen` only matches documents whose
+// author field contains "austen", not every document that mentions Austen.
+
+/// Fields describing a document
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// The title, searched for as `title:...`
+    pub title: Option<String>,
+    /// The author, searched for as `author:...`
+    pub author: Option<String>,
+    /// The year of publication, searched for as `year:...`
+    pub year: Option<u32>,
+    /// Free-form labels, each searched for as `tag:...`
+    pub tags: Vec<String>,
+}
+
+impl Metadata {
+    /// Parse metadata from a header line like `[Emma by Jane Austen 1816]` at the start of
+    /// `doc`, as found in the texts in `data/`. Returns `None` if the document does not start
+    /// with such a header.
+    pub fn from_header(doc: &str) -> Option<Metadata> {
+        let line = doc.lines().next()?.trim();
+        let mut header = line.strip_prefix('[')?.strip_suffix(']')?.trim();
+
+        let mut year = None;
+        if let Some((rest, last)) = header.rsplit_once(' ') {
+            if last.len() == 4 && last.chars().all(|c| c.is_ascii_digit()) {
+                year = last.parse().ok();
+                header = rest.trim_end();
+            }
+        }
+
+        let (title, author) = match header.rsplit_once(" by ") {
+            Some((title, author)) => (title.trim_end_matches(',').trim(), Some(author.trim())),
+            None => (header, None),
+        };
+
+        Some(Metadata {
+            title: (!title.is_empty()).then(|| title.to_string()),
+            author: author.map(str::to_string),
+            year,
+            tags: Vec::new(),
+        })
+    }
+
+    /// Whether no field is set
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+
+    /// The name and value of every field that is set. Each tag is a separate `tag` field.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        if let Some(title) = &self.title {
+            fields.push(("title", title.clone()));
+        }
+        if let Some(author) = &self.author {
+            fields.push(("author", author.clone()));
+        }
+        if let Some(year) = self.year {
+            fields.push(("year", year.to_string()));
+        }
+        for tag in self.tags.iter() {
+            fields.push(("tag", tag.clone()));
+        }
+        fields
+    }
+}
@@ -89,6 +89,37 @@ impl<K: Hash + Eq, V: Clone + Eq> ConcurrentMultiMap<K, V> {
     }
 }
 
+impl<K: Hash + Eq, V: Eq> ConcurrentMultiMap<K, V> {
+    // Remove the association between `key` and `value`, returning whether it existed. As in
+    // `set`, hash the key to find its bucket and take a writer lock of the bucket, then rebuild
+    // the linked list without the matching key-value pair.
+    pub fn remove<Q>(&self, key: &Q, value: &V) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash_val = hash_key(&key);
+        let mod_val = (hash_val % (self.buckets.len() as u64)) as usize;
+
+        let Some(bucket) = self.buckets.get(mod_val) else {
+            return false;
+        };
+
+        let mut bucket = bucket.write().unwrap();
+        let before = bucket.len();
+
+        let entries = std::mem::take(&mut *bucket);
+        *bucket = entries
+            .into_iter()
+            .filter(|(existing_key, existing_value)| {
+                existing_key.borrow() != key || existing_value != value
+            })
+            .collect();
+
+        bucket.len() != before
+    }
+}
+
 fn hash_key<K : Hash> (key: &K) -> u64 {
 
     let mut hasher = DefaultHasher::new();
@@ -9,6 +9,7 @@ use std::{
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 struct Worker {
+    #[allow(dead_code)]
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
@@ -84,7 +85,7 @@ impl ThreadPool {
     {
         if let Some(tx) = &self.sender {
             let heap_f = Box::new(f);
-            if let Err(_) = tx.send(heap_f) {
+            if tx.send(heap_f).is_err() {
                 eprintln!("Failed to send job to the worker threads");
             }
         }
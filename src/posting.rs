@@ -0,0 +1,271 @@
+use crate::database::Posting;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::RwLock;
+
+// A posting list stores the postings of a single key, sorted by document id, as a byte string.
+// Each posting is encoded as
+//
+//     [doc id delta][position count][position deltas...]
+//
+// where every number is an unsigned LEB128 varint, the doc id is the difference from the previous
+// posting's and each position is the difference from the previous position. Most of them fit in a
+// single byte, so a posting costs a few bytes instead of a list node with its own copy of the key.
+
+/// The postings of a single key, sorted by document id and stored compressed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PostingList {
+    bytes: Vec<u8>,
+    /// The number of postings in the list
+    len: usize,
+    /// The document id of the last posting, which new postings are usually appended after
+    last_doc: Option<usize>,
+}
+
+impl PostingList {
+    /// Create an empty posting list
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of postings in the list
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the list has no postings
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of bytes the list occupies on the heap
+    pub fn heap_size(&self) -> usize {
+        self.bytes.capacity()
+    }
+
+    /// Iterate over the postings in increasing order of document id
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            bytes: &self.bytes,
+            doc: 0,
+        }
+    }
+
+    /// Add `posting`, replacing the posting of the same document if there is one
+    pub fn insert(&mut self, posting: Posting) {
+        match self.last_doc {
+            Some(last) if posting.doc <= last => {
+                let mut postings: Vec<Posting> = self.iter().collect();
+                match postings.binary_search_by_key(&posting.doc, |existing| existing.doc) {
+                    Ok(index) => postings[index] = posting,
+                    Err(index) => postings.insert(index, posting),
+                }
+                *self = postings.into_iter().collect();
+            }
+            _ => self.push(posting),
+        }
+    }
+
+    /// Remove the posting of document `doc`, returning whether there was one
+    pub fn remove(&mut self, doc: usize) -> bool {
+        let before = self.len;
+        *self = self.iter().filter(|posting| posting.doc != doc).collect();
+        self.len != before
+    }
+
+    // Append `posting`, which must be for a document after every other in the list.
+    fn push(&mut self, posting: Posting) {
+        push_varint(&mut self.bytes, posting.doc - self.last_doc.unwrap_or(0));
+        push_varint(&mut self.bytes, posting.positions.len());
+
+        let mut previous = 0;
+        for position in posting.positions {
+            push_varint(&mut self.bytes, position - previous);
+            previous = position;
+        }
+
+        self.len += 1;
+        self.last_doc = Some(posting.doc);
+    }
+}
+
+impl FromIterator<Posting> for PostingList {
+    /// Collect postings that are sorted by document id into a list
+    fn from_iter<I: IntoIterator<Item = Posting>>(iter: I) -> Self {
+        let mut list = PostingList::new();
+        for posting in iter {
+            list.push(posting);
+        }
+        list.bytes.shrink_to_fit();
+        list
+    }
+}
+
+/// An iterator over the postings of a `PostingList`
+pub struct Iter<'a> {
+    bytes: &'a [u8],
+    /// The document id of the previous posting
+    doc: usize,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Posting;
+
+    fn next(&mut self) -> Option<Posting> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+
+        self.doc += next_varint(&mut self.bytes);
+        let count = next_varint(&mut self.bytes);
+
+        let mut positions = Vec::with_capacity(count);
+        let mut position = 0;
+        for _ in 0..count {
+            position += next_varint(&mut self.bytes);
+            positions.push(position);
+        }
+
+        Some(Posting {
+            doc: self.doc,
+            positions,
+        })
+    }
+}
+
+// Append `value` to `bytes` as an unsigned LEB128 varint.
+fn push_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+// Decode the varint at the start of `bytes` and advance past it.
+fn next_varint(bytes: &mut &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    while let Some((&byte, rest)) = bytes.split_first() {
+        *bytes = rest;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    value
+}
+
+/// Memory usage of the postings held in memory by a `PostingTable`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PostingStats {
+    /// The number of distinct keys
+    pub keys: usize,
+    /// The number of postings
+    pub postings: usize,
+    /// The bytes used by the keys and their compressed posting lists
+    pub compressed_bytes: usize,
+    /// The bytes the same postings would use stored uncompressed as one linked list node per
+    /// posting, each with its own copy of the key, as a `ConcurrentMultiMap` stores them
+    pub uncompressed_bytes: usize,
+}
+
+impl PostingStats {
+    /// Add the usage counted in `other` to this one
+    pub fn add(&mut self, other: PostingStats) {
+        self.keys += other.keys;
+        self.postings += other.postings;
+        self.compressed_bytes += other.compressed_bytes;
+        self.uncompressed_bytes += other.uncompressed_bytes;
+    }
+}
+
+/// A concurrent map from keys to their posting lists. Like `ConcurrentMultiMap`, the keys are
+/// spread over a fixed number of buckets that are each behind their own lock.
+pub struct PostingTable {
+    buckets: Vec<RwLock<HashMap<String, PostingList>>>,
+}
+
+impl PostingTable {
+    /// Create an empty table with the given number of buckets
+    pub fn new(bucket_count: usize) -> Self {
+        PostingTable {
+            buckets: (0..bucket_count.max(1))
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    /// Add `posting` under `key`, replacing the posting of the same document if there is one
+    pub fn insert(&self, key: String, posting: Posting) {
+        let mut bucket = self.bucket(&key).write().unwrap();
+        bucket.entry(key).or_default().insert(posting);
+    }
+
+    /// Remove the posting of document `doc` from under `key`, returning whether there was one
+    pub fn remove(&self, key: &str, doc: usize) -> bool {
+        let mut bucket = self.bucket(key).write().unwrap();
+        let Some(list) = bucket.get_mut(key) else {
+            return false;
+        };
+
+        let removed = list.remove(doc);
+        if list.is_empty() {
+            bucket.remove(key);
+        }
+        removed
+    }
+
+    /// The postings of `key`, sorted by document id
+    pub fn get(&self, key: &str) -> Vec<Posting> {
+        let bucket = self.bucket(key).read().unwrap();
+        bucket
+            .get(key)
+            .map(|list| list.iter().collect())
+            .unwrap_or_default()
+    }
+
+    /// Call `f` with every key and its posting list. Each bucket is read-locked while its keys
+    /// are visited.
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&String, &PostingList),
+    {
+        for bucket in self.buckets.iter() {
+            let bucket = bucket.read().unwrap();
+            for (key, list) in bucket.iter() {
+                f(key, list);
+            }
+        }
+    }
+
+    /// Measure the memory used by the table's postings
+    pub fn stats(&self) -> PostingStats {
+        // A linked list node holds two pointers besides its element
+        let node_size = mem::size_of::<(String, Posting)>() + 2 * mem::size_of::<usize>();
+
+        let mut stats = PostingStats::default();
+        self.for_each(|key, list| {
+            stats.keys += 1;
+            stats.postings += list.len();
+            stats.compressed_bytes +=
+                mem::size_of::<(String, PostingList)>() + key.len() + list.heap_size();
+            stats.uncompressed_bytes += list
+                .iter()
+                .map(|posting| {
+                    node_size + key.len() + posting.positions.len() * mem::size_of::<usize>()
+                })
+                .sum::<usize>();
+        });
+        stats
+    }
+
+    fn bucket(&self, key: &str) -> &RwLock<HashMap<String, PostingList>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.buckets[(hasher.finish() % self.buckets.len() as u64) as usize]
+    }
+}
@@ -0,0 +1,491 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+use std::vec::IntoIter;
+
+// A query is a boolean combination of phrases. The grammar, from lowest to highest precedence, is
+//
+//     or      := and ("OR" and)*
+//     and     := unary (["AND"] unary | "NOT" unary)*
+//     unary   := "NOT" unary | near
+//     near    := primary ["NEAR/" distance primary]
+//     primary := "(" or ")" | '"' words '"' | word+ | field | pattern
+//     field   := name ":" word | name ":" '"' words '"'
+//     name    := a word of letters, digits, underscores and dots | '"' characters '"'
+//     pattern := a word containing "*"
+//
+// Operators must be written in upper case so that lower case "and", "or" and "not" can still be
+// searched for. Consecutive words that are not separated by an operator form a single phrase, so
+// `to be or not` searches for the phrase and `hamlet AND ghost NOT horatio` finds documents
+// mentioning Hamlet and the ghost but not Horatio. A field restricts a word or phrase to one of
+// the metadata fields of a document, so `author:austen ghost` finds documents by Austen that
+// mention a ghost. Field names that are not made of letters, digits and underscores, like some
+// keys of JSON documents, are quoted, as in `"first-name":jane`. A pattern matches any word in
+// which each `*` is replaced by a run of characters, so `mac*` finds documents mentioning Macbeth
+// or Macduff. `NEAR/n` joins two words or phrases that must occur within n words of each other,
+// in either order, so `king NEAR/5 crown` finds documents where at most five words separate
+// "king" and "crown".
+
+/// A parsed search query
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    /// Documents containing the words of the phrase consecutively
+    Phrase(String),
+    /// Documents matching both queries
+    And(Box<Query>, Box<Query>),
+    /// Documents matching either query
+    Or(Box<Query>, Box<Query>),
+    /// Documents not matching the query
+    Not(Box<Query>),
+    /// Documents whose metadata field `field` contains the words of the phrase consecutively
+    Field { field: String, phrase: String },
+    /// Documents containing a word that matches the pattern, in which `*` stands for any run of
+    /// characters
+    Wildcard(String),
+    /// Documents containing both phrases, in either order, with at most `distance` words between
+    /// the end of the first and the start of the second
+    Near {
+        left: String,
+        right: String,
+        distance: usize,
+    },
+}
+
+/// The reason a query could not be parsed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// A description of what is wrong with the query
+    pub message: String,
+    /// The byte offset in the query at which the problem was found
+    pub offset: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The reason a query could not be answered
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// The query is malformed
+    Parse(ParseError),
+    /// The query contains a phrase made up only of stop words, which are not indexed
+    StopWord(String),
+    /// The regular expression is malformed or too large, for the given reason
+    Regex(String),
+    /// N-grams of `n` words were asked for, but only those of 1 to `max_ngram` words are indexed
+    NgramSize { n: usize, max_ngram: usize },
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::Parse(e) => e.fmt(f),
+            QueryError::StopWord(phrase) => write!(f, "\"{}\" only contains stop words", phrase),
+            QueryError::Regex(reason) => write!(f, "invalid regular expression: {}", reason),
+            QueryError::NgramSize { n, max_ngram } => write!(
+                f,
+                "n-grams of {} words are not indexed; the server indexes n-grams of 1 to {} words, \
+                 as set by --max-ngram",
+                n, max_ngram
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl From<ParseError> for QueryError {
+    fn from(e: ParseError) -> Self {
+        QueryError::Parse(e)
+    }
+}
+
+impl Query {
+    /// Parse `query` into a `Query`, or describe why it is malformed
+    pub fn parse(query: &str) -> Result<Query, ParseError> {
+        let tokens = lex(query)?;
+        let mut parser = Parser {
+            tokens: tokens.into_iter().peekable(),
+            end: query.len(),
+        };
+
+        if parser.tokens.peek().is_none() {
+            return Err(parser.error("the query is empty"));
+        }
+
+        let parsed = parser.or()?;
+        match parser.tokens.next() {
+            None => Ok(parsed),
+            Some((Token::RightParen, offset)) => Err(ParseError {
+                message: "unmatched ')'".to_string(),
+                offset,
+            }),
+            Some((token, offset)) => Err(ParseError {
+                message: format!("unexpected {}", token),
+                offset,
+            }),
+        }
+    }
+
+    /// Every phrase in the query that is searched for in the text of documents, including
+    /// negated ones
+    pub fn phrases(&self) -> Vec<&str> {
+        match self {
+            Query::Phrase(phrase) => vec![phrase.as_str()],
+            Query::And(left, right) | Query::Or(left, right) => {
+                let mut phrases = left.phrases();
+                phrases.extend(right.phrases());
+                phrases
+            }
+            Query::Not(query) => query.phrases(),
+            Query::Near { left, right, .. } => vec![left.as_str(), right.as_str()],
+            Query::Field { .. } | Query::Wildcard(_) => Vec::new(),
+        }
+    }
+
+    /// The phrases a matching document is expected to contain in its text, that is every phrase
+    /// in the query that is not negated or restricted to a field
+    pub fn positive_phrases(&self) -> Vec<&str> {
+        match self {
+            Query::Phrase(phrase) => vec![phrase.as_str()],
+            Query::And(left, right) | Query::Or(left, right) => {
+                let mut phrases = left.positive_phrases();
+                phrases.extend(right.positive_phrases());
+                phrases
+            }
+            Query::Near { left, right, .. } => vec![left.as_str(), right.as_str()],
+            Query::Not(_) | Query::Field { .. } | Query::Wildcard(_) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Not,
+    Word(String),
+    Quoted(String),
+    Field(String, String),
+    Pattern(String),
+    Near(usize),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::LeftParen => write!(f, "'('"),
+            Token::RightParen => write!(f, "')'"),
+            Token::And => write!(f, "'AND'"),
+            Token::Or => write!(f, "'OR'"),
+            Token::Not => write!(f, "'NOT'"),
+            Token::Word(word) => write!(f, "word '{}'", word),
+            Token::Quoted(phrase) => write!(f, "phrase \"{}\"", phrase),
+            Token::Field(field, phrase) => write!(f, "field {}:\"{}\"", field, phrase),
+            Token::Pattern(pattern) => write!(f, "pattern '{}'", pattern),
+            Token::Near(distance) => write!(f, "'NEAR/{}'", distance),
+        }
+    }
+}
+
+// Split `query` into tokens, each paired with the byte offset at which it starts.
+fn lex(query: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = query.char_indices().peekable();
+
+    while let Some(&(offset, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push((Token::LeftParen, offset));
+            }
+            ')' => {
+                chars.next();
+                tokens.push((Token::RightParen, offset));
+            }
+            '"' => {
+                chars.next();
+                let phrase = quoted(&mut chars, offset)?;
+                // A quoted field name followed by a colon, like `"first-name":jane`, names a field
+                // that is not made of letters, digits and underscores
+                let token = match chars.next_if(|&(_, c)| c == ':') {
+                    Some(_) => {
+                        let value = match chars.next_if(|&(_, c)| c == '"') {
+                            Some(_) => quoted(&mut chars, offset)?,
+                            None => word(&mut chars),
+                        };
+                        if value.is_empty() {
+                            return Err(ParseError {
+                                message: format!("expected a value for field '{}'", phrase),
+                                offset,
+                            });
+                        }
+                        Token::Field(phrase, value)
+                    }
+                    None => Token::Quoted(phrase),
+                };
+                tokens.push((token, offset));
+            }
+            _ => {
+                let word = word(&mut chars);
+
+                let token = match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ if word.starts_with("NEAR/") => match word["NEAR/".len()..].parse() {
+                        Ok(distance) => Token::Near(distance),
+                        Err(_) => {
+                            return Err(ParseError {
+                                message: "expected a number of words after 'NEAR/'".to_string(),
+                                offset,
+                            })
+                        }
+                    },
+                    _ => match field_name(&word) {
+                        // A field followed directly by a quoted phrase, like `title:"the cross"`
+                        Some(field) if field.len() + 1 == word.len() => {
+                            if chars.next_if(|&(_, c)| c == '"').is_none() {
+                                return Err(ParseError {
+                                    message: format!("expected a value for field '{}'", field),
+                                    offset,
+                                });
+                            }
+                            let phrase = quoted(&mut chars, offset)?;
+                            Token::Field(field.to_string(), phrase)
+                        }
+                        Some(field) => {
+                            Token::Field(field.to_string(), word[field.len() + 1..].to_string())
+                        }
+                        None if word.contains('*') => Token::Pattern(word),
+                        None => Token::Word(word),
+                    },
+                };
+                tokens.push((token, offset));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Read a word up to the next whitespace, parenthesis or quote.
+fn word(chars: &mut Peekable<CharIndices>) -> String {
+    let mut word = String::new();
+    while let Some((_, c)) = chars.next_if(|&(_, c)| {
+        !(c.is_whitespace() || c == '(' || c == ')' || c == '"')
+    }) {
+        word.push(c);
+    }
+    word
+}
+
+// Read the rest of a quoted phrase whose opening '"' at `offset` has already been consumed.
+fn quoted(chars: &mut Peekable<CharIndices>, offset: usize) -> Result<String, ParseError> {
+    let mut phrase = String::new();
+    loop {
+        match chars.next() {
+            Some((_, '"')) => return Ok(phrase),
+            Some((_, c)) => phrase.push(c),
+            None => {
+                return Err(ParseError {
+                    message: "unterminated '\"'".to_string(),
+                    offset,
+                })
+            }
+        }
+    }
+}
+
+// The field name of a word like `author:austen`, if it has one. Field names are made of letters,
+// digits and underscores, so words that merely contain a colon, like "10:30", are left alone. The
+// fields of nested JSON objects are named by their path, like `address.city`.
+fn field_name(word: &str) -> Option<&str> {
+    let (field, _) = word.split_once(':')?;
+    let valid = field.split('.').all(|part| {
+        part.starts_with(|c: char| c.is_alphabetic())
+            && part.chars().all(|c| c.is_alphanumeric() || c == '_')
+    });
+    valid.then_some(field)
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<(Token, usize)>>,
+    /// The length of the query, used to report errors at its end
+    end: usize,
+}
+
+impl Parser {
+    fn or(&mut self) -> Result<Query, ParseError> {
+        let mut query = self.and()?;
+
+        while self.eat(&Token::Or) {
+            let right = self.and()?;
+            query = Query::Or(Box::new(query), Box::new(right));
+        }
+
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query, ParseError> {
+        let mut query = self.unary()?;
+
+        loop {
+            if self.eat(&Token::And) {
+                let right = self.unary()?;
+                query = Query::And(Box::new(query), Box::new(right));
+            } else if self.eat(&Token::Not) {
+                let right = self.unary()?;
+                query = Query::And(Box::new(query), Box::new(Query::Not(Box::new(right))));
+            } else if self.starts_primary() {
+                let right = self.unary()?;
+                query = Query::And(Box::new(query), Box::new(right));
+            } else {
+                return Ok(query);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Query, ParseError> {
+        if self.eat(&Token::Not) {
+            let query = self.unary()?;
+            return Ok(Query::Not(Box::new(query)));
+        }
+
+        self.near()
+    }
+
+    fn near(&mut self) -> Result<Query, ParseError> {
+        let offset = self.offset();
+        let query = self.primary()?;
+
+        let Some(&(Token::Near(distance), _)) = self.tokens.peek() else {
+            return Ok(query);
+        };
+        self.tokens.next();
+
+        let right_offset = self.offset();
+        let right = self.primary()?;
+        match (query, right) {
+            (Query::Phrase(left), Query::Phrase(right)) => Ok(Query::Near {
+                left,
+                right,
+                distance,
+            }),
+            (Query::Phrase(_), _) => Err(ParseError {
+                message: format!("'NEAR/{}' must be followed by a word or phrase", distance),
+                offset: right_offset,
+            }),
+            _ => Err(ParseError {
+                message: format!("'NEAR/{}' must follow a word or phrase", distance),
+                offset,
+            }),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Query, ParseError> {
+        match self.tokens.next() {
+            Some((Token::LeftParen, offset)) => {
+                let query = self.or()?;
+                if self.eat(&Token::RightParen) {
+                    Ok(query)
+                } else {
+                    Err(ParseError {
+                        message: "unmatched '('".to_string(),
+                        offset,
+                    })
+                }
+            }
+            Some((Token::Quoted(phrase), offset)) => {
+                if phrase.trim().is_empty() {
+                    return Err(ParseError {
+                        message: "empty phrase".to_string(),
+                        offset,
+                    });
+                }
+                Ok(Query::Phrase(phrase))
+            }
+            Some((Token::Field(field, phrase), offset)) => {
+                if phrase.trim().is_empty() {
+                    return Err(ParseError {
+                        message: format!("empty value for field '{}'", field),
+                        offset,
+                    });
+                }
+                Ok(Query::Field { field, phrase })
+            }
+            Some((Token::Pattern(pattern), offset)) => {
+                if pattern.chars().all(|c| c == '*') {
+                    return Err(ParseError {
+                        message: format!("pattern '{}' would match every word", pattern),
+                        offset,
+                    });
+                }
+                Ok(Query::Wildcard(pattern))
+            }
+            Some((Token::Word(word), _)) => {
+                let mut words = vec![word];
+                while let Some((Token::Word(_), _)) = self.tokens.peek() {
+                    if let Some((Token::Word(word), _)) = self.tokens.next() {
+                        words.push(word);
+                    }
+                }
+                Ok(Query::Phrase(words.join(" ")))
+            }
+            Some((token, offset)) => Err(ParseError {
+                message: format!("expected a word, phrase or '(' but found {}", token),
+                offset,
+            }),
+            None => Err(self.error("unexpected end of query")),
+        }
+    }
+
+    // The offset of the next token, or the end of the query if there is none
+    fn offset(&mut self) -> usize {
+        self.tokens.peek().map_or(self.end, |&(_, offset)| offset)
+    }
+
+    // Consume the next token if it is `token`
+    fn eat(&mut self, token: &Token) -> bool {
+        match self.tokens.peek() {
+            Some((next, _)) if next == token => {
+                self.tokens.next();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Whether the next token can begin a primary expression, which means it is implicitly joined
+    // to the preceding expression with AND
+    fn starts_primary(&mut self) -> bool {
+        matches!(
+            self.tokens.peek(),
+            Some((
+                Token::LeftParen
+                    | Token::Word(_)
+                    | Token::Quoted(_)
+                    | Token::Field(..)
+                    | Token::Pattern(_),
+                _
+            ))
+        )
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            message: message.to_string(),
+            offset: self.end,
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::database::Posting;
+use crate::snapshot::{
+    read_postings, read_string, read_varint, write_postings, write_string, write_varint,
+};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// A segment file holds the postings of a sorted run of keys, and is never changed once written.
+// The layout is
+//
+//     magic: b"NGRAMSEG"  version: u32
+//     for each key in sorted order: its postings, as written by `write_postings`
+//     key count, then for each key: the key, and the offset and length of its postings
+//     the offset of the key directory: u64
+//
+// The key directory is read into memory when the segment is opened, and postings are read from
+// the file as they are looked up. A posting with no positions is a tombstone: it records that
+// the key was removed from the document after an older segment was written.
+
+const MAGIC: &[u8; 8] = b"NGRAMSEG";
+const VERSION: u32 = 1;
+/// The size in bytes of the magic and version at the start of a segment file
+const HEADER_LEN: u64 = 12;
+/// The size in bytes of the directory offset at the end of a segment file
+const FOOTER_LEN: u64 = 8;
+
+/// An immutable, sorted run of the reverse index stored in a file
+pub struct Segment {
+    path: PathBuf,
+    file: File,
+    /// The size of the file in bytes
+    size: u64,
+    /// The offset and length in the file of the postings of each key
+    directory: BTreeMap<String, (u64, usize)>,
+    /// Set once the segment has been merged into another, so that its file is removed when the
+    /// last search using it is done
+    obsolete: AtomicBool,
+}
+
+impl Segment {
+    /// Open the segment file at `path`, reading its key directory
+    pub fn open(path: &Path) -> io::Result<Segment> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if len < HEADER_LEN + FOOTER_LEN {
+            return Err(invalid("segment file is too short"));
+        }
+
+        let mut header = [0; HEADER_LEN as usize];
+        read_exact_at(&file, &mut header, 0)?;
+        if &header[..8] != MAGIC || header[8..] != VERSION.to_be_bytes() {
+            return Err(invalid("not a segment file"));
+        }
+
+        let mut footer = [0; FOOTER_LEN as usize];
+        read_exact_at(&file, &mut footer, len - FOOTER_LEN)?;
+        let directory_offset = u64::from_be_bytes(footer);
+        if directory_offset < HEADER_LEN || directory_offset > len - FOOTER_LEN {
+            return Err(invalid("segment directory is out of bounds"));
+        }
+
+        let mut bytes = vec![0; (len - FOOTER_LEN - directory_offset) as usize];
+        read_exact_at(&file, &mut bytes, directory_offset)?;
+        let mut reader = Cursor::new(bytes);
+
+        let count = read_varint(&mut reader)?;
+        let mut directory = BTreeMap::new();
+        for _ in 0..count {
+            let key = read_string(&mut reader)?;
+            let offset = read_varint(&mut reader)?;
+            let len = usize::try_from(read_varint(&mut reader)?)
+                .map_err(|_| invalid("length is too large"))?;
+            if offset.saturating_add(len as u64) > directory_offset {
+                return Err(invalid("postings are out of bounds"));
+            }
+            directory.insert(key, (offset, len));
+        }
+
+        Ok(Segment {
+            path: path.to_path_buf(),
+            file,
+            size: len,
+            directory,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    /// Read the postings of `key`, including tombstones, sorted by document id. Keys that are not
+    /// in the segment have no postings.
+    pub fn get(&self, key: &str) -> io::Result<Vec<Posting>> {
+        let Some(&(offset, len)) = self.directory.get(key) else {
+            return Ok(Vec::new());
+        };
+
+        let mut bytes = vec![0; len];
+        read_exact_at(&self.file, &mut bytes, offset)?;
+        read_postings(&mut Cursor::new(bytes))
+    }
+
+    /// Every key in the segment, in sorted order
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.directory.keys()
+    }
+
+    /// The path of the segment file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The size of the segment file in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Mark the segment as replaced, so that its file is removed once it is no longer in use
+    pub fn retire(&self) {
+        self.obsolete.store(true, Ordering::Release);
+    }
+}
+
+/// Writes a new segment file one key at a time, so that segments can be merged without holding
+/// all of their postings in memory
+pub struct SegmentWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// The offset and length of the postings of each key written so far
+    directory: Vec<(String, u64, usize)>,
+    /// The offset at which the next postings will be written
+    offset: u64,
+}
+
+impl SegmentWriter {
+    /// Create a new segment file at `path`, replacing any file already there
+    pub fn create(path: &Path) -> io::Result<SegmentWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+
+        Ok(SegmentWriter {
+            path: path.to_path_buf(),
+            writer,
+            directory: Vec::new(),
+            offset: HEADER_LEN,
+        })
+    }
+
+    /// Add the postings of `key`, sorted by document id. Keys must be added in increasing order.
+    pub fn add(&mut self, key: &str, postings: &[Posting]) -> io::Result<()> {
+        debug_assert!(self
+            .directory
+            .last()
+            .is_none_or(|(last, _, _)| last.as_str() < key));
+
+        let mut bytes = Vec::new();
+        write_postings(&mut bytes, postings)?;
+        self.writer.write_all(&bytes)?;
+
+        self.directory
+            .push((key.to_string(), self.offset, bytes.len()));
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Write the key directory, flush the file to disk and open it as a segment
+    pub fn finish(mut self) -> io::Result<Segment> {
+        write_varint(&mut self.writer, self.directory.len() as u64)?;
+        for (key, offset, len) in self.directory.iter() {
+            write_string(&mut self.writer, key)?;
+            write_varint(&mut self.writer, *offset)?;
+            write_varint(&mut self.writer, *len as u64)?;
+        }
+        self.writer.write_all(&self.offset.to_be_bytes())?;
+        self.writer.into_inner()?.sync_all()?;
+
+        Segment::open(&self.path)
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::Acquire) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                eprintln!("Failed to remove segment {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Fill `buf` with the bytes of `file` starting at `offset`. The read does not depend on the
+/// file cursor, so several threads can read the same file at once.
+#[cfg(unix)]
+pub fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
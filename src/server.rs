@@ -1,17 +1,24 @@
-use crate::database::Database;
+use crate::database::{Database, DatabaseConfig};
+use crate::json::JsonError;
 use crate::message::*;
 use crate::pool::ThreadPool;
-use std::io::Write;
+use crate::query::QueryError;
+use std::io::{self, Write};
 use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
 use std::thread;
+use std::time::Duration;
 
 /// The number of workers in the server's thread pool
 const WORKERS: usize = 16;
 
+/// The largest number of spellings suggested for a search that found nothing
+const SUGGESTIONS: usize = 5;
+
 // TODO:
 // Implement the `process_message` function. This function should take a `ServerState`, a `Request`,
 // and a `TcpStream`. It should process the request and write the response to the stream.
@@ -22,12 +29,46 @@ fn process_message(state: Arc<ServerState>, request: Request, mut stream: TcpStr
     
     let response = match request {
         Request::Publish { doc } => {
-            let doc_id = state.database.publish(doc);
-            Response::PublishSuccess(doc_id)
+            match state.database.publish_with_status(doc) {
+                Ok((doc_id, deduplicated)) => Response::PublishSuccess(doc_id, deduplicated),
+                Err(e) => change_failure("publish a document", e),
+            }
         },
-        Request::Search { word } => {
-            let results = state.database.search(&word);
-            Response::SearchSuccess(results)
+        Request::Search { word, options } if options.snippets => {
+            match state.database.search_with_snippets(&word, options.offset, options.limit) {
+                Ok((0, hits)) => {
+                    no_results(&state, &word, Response::SearchHits { total: 0, hits })
+                },
+                Ok((total, hits)) => Response::SearchHits { total, hits },
+                Err(e) => query_failure(e),
+            }
+        },
+        Request::Search { word, options } if options.spans => {
+            match state.database.query_spans(&word, options.offset, options.limit) {
+                Ok((0, spans)) => {
+                    no_results(&state, &word, Response::SearchSpans { total: 0, spans })
+                },
+                Ok((total, spans)) => Response::SearchSpans { total, spans },
+                Err(e) => query_failure(e),
+            }
+        },
+        Request::Search { word, options } if options.is_paged() => {
+            match state.database.query_page(&word, options.offset, options.limit) {
+                Ok((0, ids)) => {
+                    no_results(&state, &word, Response::SearchPage { total: 0, ids })
+                },
+                Ok((total, ids)) => Response::SearchPage { total, ids },
+                Err(e) => query_failure(e),
+            }
+        },
+        Request::Search { word, .. } => {
+            match state.database.query(&word) {
+                Ok(results) if results.is_empty() => {
+                    no_results(&state, &word, Response::SearchSuccess(results))
+                },
+                Ok(results) => Response::SearchSuccess(results),
+                Err(e) => query_failure(e),
+            }
         },
         Request::Retrieve { id } => {
             match state.database.retrieve(id) {
@@ -36,7 +77,90 @@ fn process_message(state: Arc<ServerState>, request: Request, mut stream: TcpStr
                 },
                 None => Response::Failure
             }
-        }
+        },
+        Request::FuzzySearch { word, threshold } => {
+            let results = state.database.fuzzy_search(&word, threshold);
+            Response::SearchSuccess(results)
+        },
+        Request::RankedSearch { query } => {
+            match state.database.ranked_search(&query) {
+                Ok(results) => Response::RankedSearchSuccess(results),
+                Err(e) => query_failure(e),
+            }
+        },
+        Request::Delete { id } => {
+            match state.database.delete(id) {
+                Ok(true) => Response::DeleteSuccess,
+                Ok(false) => Response::Failure,
+                Err(e) => change_failure("delete a document", e),
+            }
+        },
+        Request::Update { id, doc } => {
+            match state.database.update(id, doc) {
+                Ok(true) => Response::UpdateSuccess,
+                Ok(false) => Response::Failure,
+                Err(e) => change_failure("update a document", e),
+            }
+        },
+        Request::Snapshot { path } => {
+            match snapshot_path(&state, &path) {
+                Ok(full_path) => match state.database.snapshot(&full_path) {
+                    Ok(()) => Response::SnapshotSuccess,
+                    Err(e) => {
+                        eprintln!("Failed to write snapshot to {}: {}", full_path.display(), e);
+                        Response::Failure
+                    }
+                },
+                Err(reason) => {
+                    eprintln!("Refused to write snapshot to {}: {}", path, reason);
+                    Response::Failure
+                }
+            }
+        },
+        Request::IndexStats => Response::IndexStatsSuccess(state.database.index_stats()),
+        Request::PublishWithMetadata { doc, metadata } => {
+            match state.database.publish_with_metadata(doc, metadata) {
+                Ok((doc_id, deduplicated)) => Response::PublishSuccess(doc_id, deduplicated),
+                Err(e) => change_failure("publish a document", e),
+            }
+        },
+        Request::RetrieveMetadata { id } => {
+            match state.database.metadata(id) {
+                Some(metadata) => Response::MetadataSuccess(metadata),
+                None => Response::Failure,
+            }
+        },
+        Request::SearchRegex { pattern } => {
+            match state.database.search_regex(&pattern) {
+                Ok(matches) => Response::RegexSearchSuccess {
+                    ids: matches.ids,
+                    terms: matches.terms,
+                    truncated: matches.truncated,
+                },
+                Err(e) => query_failure(e),
+            }
+        },
+        Request::PublishJson { doc } => {
+            match state.database.publish_json(doc) {
+                Ok((doc_id, deduplicated)) => Response::PublishSuccess(doc_id, deduplicated),
+                Err(e) if e.get_ref().is_some_and(|inner| inner.is::<JsonError>()) => {
+                    Response::InvalidDocument(e.to_string())
+                },
+                Err(e) => change_failure("publish a document", e),
+            }
+        },
+        Request::NgramStats { ngram } => {
+            match state.database.ngram_stats(&ngram) {
+                Ok(stats) => Response::NgramStatsSuccess(stats),
+                Err(e) => query_failure(e),
+            }
+        },
+        Request::TopNgrams { n, k } => {
+            match state.database.top_ngrams(n, k) {
+                Ok(ngrams) => Response::TopNgramsSuccess(ngrams),
+                Err(e) => query_failure(e),
+            }
+        },
     };
 
     let response_bytes = response.to_bytes();
@@ -47,6 +171,55 @@ fn process_message(state: Arc<ServerState>, request: Request, mut stream: TcpStr
 
 }
 
+// Answer a search for `query` that found no documents with suggested spellings of its words, or
+// with `empty` if there are none
+fn no_results(state: &ServerState, query: &str, empty: Response) -> Response {
+    let suggestions = state.database.suggest(query, SUGGESTIONS);
+    if suggestions.is_empty() {
+        empty
+    } else {
+        Response::SearchSuggestions(suggestions)
+    }
+}
+
+// Report a change to the database that could not be stored, and was therefore not made
+fn change_failure(action: &str, error: io::Error) -> Response {
+    eprintln!("Failed to {}: {}", action, error);
+    Response::Failure
+}
+
+// Describe why a query could not be answered
+fn query_failure(error: QueryError) -> Response {
+    match error {
+        QueryError::Parse(e) => Response::InvalidQuery(e.to_string()),
+        QueryError::StopWord(phrase) => Response::StopWord(phrase),
+        e @ (QueryError::Regex(_) | QueryError::NgramSize { .. }) => {
+            Response::InvalidQuery(e.to_string())
+        },
+    }
+}
+
+// Resolve the path a client asked for a snapshot to be written to inside the snapshot directory,
+// or describe why it may not be written there
+fn snapshot_path(state: &ServerState, path: &str) -> Result<PathBuf, &'static str> {
+    let Some(dir) = &state.snapshot_dir else {
+        return Err("snapshots are disabled; start the server with --snapshot-dir to allow them");
+    };
+
+    let path = Path::new(path);
+    if path.file_name().is_none() {
+        return Err("the path does not name a file");
+    }
+    // Only plain file and directory names are allowed, so the path can't leave the directory
+    let contained = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !contained {
+        return Err("the path must be relative and must not contain `..`");
+    }
+    Ok(dir.join(path))
+}
+
 /// A struct that contains the state of the server
 struct ServerState {
     /// The database that the server uses to store documents
@@ -55,13 +228,16 @@ struct ServerState {
     pool: ThreadPool,
     /// A flag that indicates whether the server has been stopped
     is_stopped: AtomicBool,
+    /// The directory snapshots requested by clients are written to, if they are allowed at all
+    snapshot_dir: Option<PathBuf>,
 }
 impl ServerState {
-    fn new() -> Self {
+    fn new(database: Database) -> Self {
         Self {
-            database: Database::new(),
+            database,
             pool: ThreadPool::new(WORKERS),
             is_stopped: AtomicBool::new(false),
+            snapshot_dir: None,
         }
     }
 }
@@ -69,6 +245,11 @@ impl ServerState {
 pub struct Server {
     state: Arc<ServerState>,
 }
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 fn handle_connection(state: Arc<ServerState>, mut stream: TcpStream) {
     // Attempt to deserialize a request from the stream
@@ -84,7 +265,40 @@ impl Server {
     // TODO:
     // Create a new server by using the `ServerState::new` function
     pub fn new() -> Self {
-        let server_state = ServerState::new();
+        Self::with_config(DatabaseConfig::default())
+    }
+
+    /// Create a new server whose database indexes documents according to `config`
+    pub fn with_config(config: DatabaseConfig) -> Self {
+        Self::with_database(Database::new(config))
+    }
+
+    /// Create a new server whose database is durably stored in `data_dir`, restoring the
+    /// documents it held when it was last run
+    pub fn open(config: DatabaseConfig, data_dir: &Path) -> io::Result<Self> {
+        Ok(Self::with_database(Database::open(config, data_dir)?))
+    }
+
+    /// Create a new in-memory server whose database is restored from the snapshot at `path`
+    pub fn restore(config: DatabaseConfig, path: &Path) -> io::Result<Self> {
+        Ok(Self::with_database(Database::restore(config, path)?))
+    }
+
+    /// Allow clients to write snapshots of the database to files inside `dir`. Without a snapshot
+    /// directory, every snapshot request fails.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server has already been run.
+    pub fn with_snapshot_dir(mut self, dir: PathBuf) -> Self {
+        Arc::get_mut(&mut self.state)
+            .expect("the snapshot directory must be set before the server is run")
+            .snapshot_dir = Some(dir);
+        self
+    }
+
+    fn with_database(database: Database) -> Self {
+        let server_state = ServerState::new(database);
         let state = Arc::new(server_state);
 
         Server {
@@ -159,7 +373,7 @@ impl Server {
         self.listen(port);
 
         while !self.state.is_stopped.load(Ordering::SeqCst) {
-
+            thread::sleep(Duration::from_millis(10));
         }
     }
     pub fn stop(&self) {
@@ -0,0 +1,322 @@
+use crate::database::Posting;
+use crate::metadata::Metadata;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Write};
+
+// A snapshot file holds every document of a database together with its reverse index, so that a
+// database can be restored without tokenizing its documents again. The layout is
+//
+//     magic: b"NGRAMSNP"  version: u32
+//     document count, then for each document:
+//         0 if it was deleted, or 1 followed by its text and indexed word count
+//     key count, then for each key in sorted order:
+//         the key, the number of postings, then for each posting:
+//             the document id, the number of positions, and the positions
+//     metadata count, then for each document with metadata, in order of id:
+//         the document id, the title and author, each 0 if missing or 1 followed by the text,
+//         the year, 0 if missing or 1 followed by the year, and the number of tags and each tag
+//     JSON document count, then the id of each document published as JSON, in increasing order
+//         and delta encoded
+//
+// Lengths, counts and ids are unsigned LEB128 varints, and text is a varint length followed by
+// UTF-8 bytes. Postings are sorted by document id, and both document ids and positions are
+// stored as the difference from the previous one, which keeps most of them to a single byte.
+// Version 1 snapshots end after the keys, and restore without metadata. Version 2 snapshots end
+// after the metadata, and hold no JSON documents. Before version 4, the keys of metadata fields
+// joined the field name and word with ':' instead of a NUL character.
+
+const MAGIC: &[u8; 8] = b"NGRAMSNP";
+/// The version of the snapshot format written by `Snapshot::write_to`
+pub const VERSION: u32 = 4;
+
+/// The contents of a database as stored in a snapshot file
+pub struct Snapshot {
+    /// The version of the format the snapshot was read from. Snapshots are always written in
+    /// the current `VERSION`.
+    pub version: u32,
+    /// The text and indexed word count of each document, indexed by document id. Deleted
+    /// documents are `None`.
+    pub documents: Vec<Option<(String, usize)>>,
+    /// The postings of every key in the reverse index
+    pub postings: BTreeMap<String, Vec<Posting>>,
+    /// The metadata of the documents that have any, by document id
+    pub metadata: BTreeMap<usize, Metadata>,
+    /// The ids of the documents that were published as JSON
+    pub json: BTreeSet<usize>,
+}
+
+impl Snapshot {
+    /// Write the snapshot to `writer`
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+
+        write_varint(&mut writer, self.documents.len() as u64)?;
+        for document in self.documents.iter() {
+            match document {
+                Some((text, length)) => {
+                    writer.write_all(&[1])?;
+                    write_string(&mut writer, text)?;
+                    write_varint(&mut writer, *length as u64)?;
+                }
+                None => writer.write_all(&[0])?,
+            }
+        }
+
+        write_varint(&mut writer, self.postings.len() as u64)?;
+        for (key, postings) in self.postings.iter() {
+            write_string(&mut writer, key)?;
+            write_postings(&mut writer, postings)?;
+        }
+
+        write_varint(&mut writer, self.metadata.len() as u64)?;
+        for (&id, metadata) in self.metadata.iter() {
+            write_varint(&mut writer, id as u64)?;
+            write_metadata(&mut writer, metadata)?;
+        }
+
+        write_varint(&mut writer, self.json.len() as u64)?;
+        let mut previous = 0;
+        for &id in self.json.iter() {
+            write_varint(&mut writer, (id - previous) as u64)?;
+            previous = id;
+        }
+
+        writer.flush()
+    }
+
+    /// Read a snapshot written by `write_to` from `reader`
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Snapshot> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_be_bytes(version);
+        if &magic != MAGIC || !(1..=VERSION).contains(&version) {
+            return Err(invalid("not a snapshot file"));
+        }
+
+        let count = read_len(&mut reader)?;
+        let mut documents = Vec::new();
+        for _ in 0..count {
+            let mut tag = [0; 1];
+            reader.read_exact(&mut tag)?;
+            documents.push(match tag[0] {
+                0 => None,
+                1 => {
+                    let text = read_string(&mut reader)?;
+                    let length = read_len(&mut reader)?;
+                    Some((text, length))
+                }
+                _ => return Err(invalid("unknown document tag")),
+            });
+        }
+
+        let count = read_len(&mut reader)?;
+        let mut postings = BTreeMap::new();
+        for _ in 0..count {
+            let key = read_string(&mut reader)?;
+            let key_postings = read_postings(&mut reader)?;
+            if key_postings
+                .iter()
+                .any(|posting| posting.doc >= documents.len())
+            {
+                return Err(invalid("posting for an unknown document"));
+            }
+
+            postings.insert(key, key_postings);
+        }
+
+        let mut metadata = BTreeMap::new();
+        if version >= 2 {
+            let count = read_len(&mut reader)?;
+            for _ in 0..count {
+                let id = read_len(&mut reader)?;
+                if id >= documents.len() {
+                    return Err(invalid("metadata for an unknown document"));
+                }
+                metadata.insert(id, read_metadata(&mut reader)?);
+            }
+        }
+
+        let mut json = BTreeSet::new();
+        if version >= 3 {
+            let mut id: usize = 0;
+            for _ in 0..read_len(&mut reader)? {
+                id = id
+                    .checked_add(read_len(&mut reader)?)
+                    .ok_or_else(|| invalid("document id is too large"))?;
+                if !matches!(documents.get(id), Some(Some(_))) {
+                    return Err(invalid("JSON flag for an unknown document"));
+                }
+                json.insert(id);
+            }
+        }
+
+        Ok(Snapshot {
+            version,
+            documents,
+            postings,
+            metadata,
+            json,
+        })
+    }
+}
+
+/// Write `value` to `writer` as an unsigned LEB128 varint: seven bits per byte, least
+/// significant first, with the high bit set on every byte but the last
+pub fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// Read an unsigned LEB128 varint written by `write_varint` from `reader`
+pub fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0; 1];
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint is too long"))
+}
+
+/// Write `postings`, which must be sorted by document id, to `writer` as their count followed by
+/// each document id and its positions, delta encoded
+pub fn write_postings<W: Write>(writer: &mut W, postings: &[Posting]) -> io::Result<()> {
+    write_varint(writer, postings.len() as u64)?;
+
+    let mut previous_doc = 0;
+    for posting in postings.iter() {
+        write_varint(writer, (posting.doc - previous_doc) as u64)?;
+        previous_doc = posting.doc;
+
+        write_varint(writer, posting.positions.len() as u64)?;
+        let mut previous_position = 0;
+        for &position in posting.positions.iter() {
+            write_varint(writer, (position - previous_position) as u64)?;
+            previous_position = position;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read postings written by `write_postings` from `reader`
+pub fn read_postings<R: Read>(reader: &mut R) -> io::Result<Vec<Posting>> {
+    let count = read_len(reader)?;
+
+    let mut postings = Vec::new();
+    let mut doc: usize = 0;
+    for _ in 0..count {
+        doc = doc
+            .checked_add(read_len(reader)?)
+            .ok_or_else(|| invalid("document id is too large"))?;
+
+        let position_count = read_len(reader)?;
+        let mut positions = Vec::new();
+        let mut position: usize = 0;
+        for _ in 0..position_count {
+            position = position
+                .checked_add(read_len(reader)?)
+                .ok_or_else(|| invalid("position is too large"))?;
+            positions.push(position);
+        }
+
+        postings.push(Posting { doc, positions });
+    }
+
+    Ok(postings)
+}
+
+/// Write `metadata` to `writer` as its title and author, each 0 if missing or 1 followed by the
+/// text, its year, 0 if missing or 1 followed by the year, and its number of tags and each tag
+pub fn write_metadata<W: Write>(writer: &mut W, metadata: &Metadata) -> io::Result<()> {
+    for field in [&metadata.title, &metadata.author] {
+        match field {
+            Some(value) => {
+                writer.write_all(&[1])?;
+                write_string(writer, value)?;
+            }
+            None => writer.write_all(&[0])?,
+        }
+    }
+    match metadata.year {
+        Some(year) => {
+            writer.write_all(&[1])?;
+            write_varint(writer, year as u64)?;
+        }
+        None => writer.write_all(&[0])?,
+    }
+    write_varint(writer, metadata.tags.len() as u64)?;
+    for tag in metadata.tags.iter() {
+        write_string(writer, tag)?;
+    }
+    Ok(())
+}
+
+/// Read metadata written by `write_metadata` from `reader`
+pub fn read_metadata<R: Read>(reader: &mut R) -> io::Result<Metadata> {
+    let mut metadata = Metadata::default();
+    for field in [&mut metadata.title, &mut metadata.author] {
+        if read_flag(reader)? {
+            *field = Some(read_string(reader)?);
+        }
+    }
+    if read_flag(reader)? {
+        let year = read_varint(reader)?;
+        metadata.year = Some(u32::try_from(year).map_err(|_| invalid("year is too large"))?);
+    }
+    for _ in 0..read_len(reader)? {
+        metadata.tags.push(read_string(reader)?);
+    }
+    Ok(metadata)
+}
+
+/// Write `s` to `writer` as its length in bytes followed by its UTF-8 bytes
+pub fn write_string<W: Write>(writer: &mut W, s: &str) -> io::Result<()> {
+    write_varint(writer, s.len() as u64)?;
+    writer.write_all(s.as_bytes())
+}
+
+/// Read a string written by `write_string` from `reader`
+pub fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let len = read_len(reader)?;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("text is not valid UTF-8"))
+}
+
+/// Read a length, count or id written with `write_varint` from `reader`, failing if it does not
+/// fit in a `usize`
+pub fn read_len<R: Read>(reader: &mut R) -> io::Result<usize> {
+    usize::try_from(read_varint(reader)?).map_err(|_| invalid("length is too large"))
+}
+
+/// Read a byte from `reader` that must be 0 or 1
+pub fn read_flag<R: Read>(reader: &mut R) -> io::Result<bool> {
+    let mut flag = [0; 1];
+    reader.read_exact(&mut flag)?;
+    match flag[0] {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(invalid("unknown flag")),
+    }
+}
+
+/// An `InvalidData` error with the given message
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,86 @@
+// A snippet shows a search hit in context without retrieving the whole document. It is cut from
+// the text around the first match, widened by up to `CONTEXT` bytes on either side and trimmed to
+// whole words, and records where every match inside it is so that clients can highlight them.
+
+/// The number of bytes of context a snippet includes on either side of the first match
+pub const CONTEXT: usize = 80;
+
+/// A short excerpt of a document around a match
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snippet {
+    /// The excerpt, with line breaks replaced by spaces
+    pub text: String,
+    /// The byte ranges in `text` of the matches it contains, in order
+    pub matches: Vec<(usize, usize)>,
+}
+
+/// A document matching a search, with a snippet of its text
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchHit {
+    /// The id of the document
+    pub id: usize,
+    /// The text around the first match, or the start of the document if none of its text matched
+    pub snippet: Snippet,
+}
+
+impl Snippet {
+    /// Cut a snippet from `text` around the first of `matches`, which are byte ranges in `text`
+    /// sorted by start. Without matches, the snippet is taken from the start of `text`.
+    pub fn new(text: &str, matches: &[(usize, usize)]) -> Snippet {
+        let (first_start, first_end) = matches.first().copied().unwrap_or((0, 0));
+
+        let mut start = first_start.saturating_sub(CONTEXT);
+        let mut end = (first_end + CONTEXT).min(text.len());
+        while !text.is_char_boundary(start) {
+            start += 1;
+        }
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        // Don't start or end in the middle of a word
+        if start > 0 {
+            let space = text[start..first_start]
+                .char_indices()
+                .find(|(_, c)| c.is_whitespace());
+            if let Some((offset, space)) = space {
+                start += offset + space.len_utf8();
+            }
+        }
+        if end < text.len() {
+            if let Some(space) = text[first_end..end].rfind(char::is_whitespace) {
+                end = first_end + space;
+            }
+        }
+
+        let excerpt = &text[start..end];
+        Snippet {
+            text: excerpt.replace(['\r', '\n'], " "),
+            matches: matches
+                .iter()
+                .filter(|&&(match_start, match_end)| start <= match_start && match_end <= end)
+                .map(|&(match_start, match_end)| (match_start - start, match_end - start))
+                .collect(),
+        }
+    }
+
+    /// The text of the snippet with every match wrapped in `before` and `after`
+    pub fn highlight(&self, before: &str, after: &str) -> String {
+        let mut highlighted = String::new();
+        let mut last = 0;
+        for &(start, end) in self.matches.iter() {
+            // Overlapping matches are highlighted as one
+            let start = start.max(last);
+            if start >= end {
+                continue;
+            }
+            highlighted.push_str(&self.text[last..start]);
+            highlighted.push_str(before);
+            highlighted.push_str(&self.text[start..end]);
+            highlighted.push_str(after);
+            last = end;
+        }
+        highlighted.push_str(&self.text[last..]);
+        highlighted
+    }
+}
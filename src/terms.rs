@@ -0,0 +1,145 @@
+use std::collections::BTreeSet;
+use std::sync::RwLock;
+
+// The reverse index hashes its keys into buckets, so it can only look words up one at a time.
+// The term dictionary keeps every indexed word in sorted order beside it, so that all words with
+// a given prefix are next to each other. A second copy of each word, spelled backwards, does the
+// same for suffixes. Words are never removed, because a word may still occur in other documents
+// when one is deleted. Searches pass a filter that skips the words left without postings, so that
+// they never take the place of live words under an expansion limit.
+
+/// A sorted set of the words in an index, which can be searched by wildcard patterns
+#[derive(Default)]
+pub struct TermDictionary {
+    terms: RwLock<Terms>,
+}
+
+#[derive(Default)]
+struct Terms {
+    /// Every word
+    forward: BTreeSet<String>,
+    /// Every word spelled backwards
+    reversed: BTreeSet<String>,
+}
+
+impl TermDictionary {
+    /// Create an empty dictionary
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `term` to the dictionary if it is not there yet
+    pub fn insert(&self, term: &str) {
+        if self.terms.read().unwrap().forward.contains(term) {
+            return;
+        }
+
+        let mut terms = self.terms.write().unwrap();
+        if terms.forward.insert(term.to_string()) {
+            terms.reversed.insert(term.chars().rev().collect());
+        }
+    }
+
+    /// The number of words in the dictionary
+    pub fn len(&self) -> usize {
+        self.terms.read().unwrap().forward.len()
+    }
+
+    /// Whether the dictionary has no words
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Find the words matching `pattern`, in which `*` stands for any run of characters, for
+    /// which `keep` returns true, in sorted order. At most `limit` words are returned. If more
+    /// match, the ones kept are those found first when scanning the dictionary from the longer of
+    /// the pattern's literal prefix and suffix.
+    pub fn expand<F>(&self, pattern: &str, limit: usize, mut keep: F) -> Vec<String>
+    where
+        F: FnMut(&str) -> bool,
+    {
+        let prefix = pattern.split('*').next().unwrap_or_default();
+        let suffix = pattern.rsplit('*').next().unwrap_or_default();
+        let terms = self.terms.read().unwrap();
+
+        let mut matches: Vec<String> = if suffix.len() > prefix.len() {
+            let reversed_suffix: String = suffix.chars().rev().collect();
+            terms
+                .reversed
+                .range(reversed_suffix.clone()..)
+                .take_while(|term| term.starts_with(&reversed_suffix))
+                .map(|term| term.chars().rev().collect::<String>())
+                .filter(|term| wildcard_match(pattern, term) && keep(term))
+                .take(limit)
+                .collect()
+        } else {
+            terms
+                .forward
+                .range(prefix.to_string()..)
+                .take_while(|term| term.starts_with(prefix))
+                .filter(|term| wildcard_match(pattern, term) && keep(term))
+                .take(limit)
+                .cloned()
+                .collect()
+        };
+
+        matches.sort_unstable();
+        matches
+    }
+
+    /// Call `f` with every word in sorted order. The dictionary is read-locked meanwhile.
+    pub fn for_each<F>(&self, f: F)
+    where
+        F: FnMut(&String),
+    {
+        self.terms.read().unwrap().forward.iter().for_each(f);
+    }
+
+    /// Find the words for which `matches` returns true by scanning the whole dictionary in sorted
+    /// order. At most `limit` words are returned, together with whether more words matched.
+    pub fn find<F>(&self, limit: usize, mut matches: F) -> (Vec<String>, bool)
+    where
+        F: FnMut(&str) -> bool,
+    {
+        let terms = self.terms.read().unwrap();
+        let mut found: Vec<String> = terms
+            .forward
+            .iter()
+            .filter(|term| matches(term))
+            .take(limit.saturating_add(1))
+            .cloned()
+            .collect();
+
+        let truncated = found.len() > limit;
+        found.truncate(limit);
+        (found, truncated)
+    }
+}
+
+/// Whether `term` matches `pattern`, in which `*` stands for any run of characters, including
+/// none, and every other character stands for itself
+pub fn wildcard_match(pattern: &str, term: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let term: Vec<char> = term.chars().collect();
+
+    // Match greedily, and on a mismatch let the last `*` swallow one more character
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < term.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == term[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
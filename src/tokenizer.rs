@@ -0,0 +1,93 @@
+use rust_stemmers::{Algorithm, Stemmer};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A word extracted from a text, in the normalized form that is stored in the index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    /// The normalized word
+    pub text: String,
+    /// The byte offset in the original text at which the word starts
+    pub start: usize,
+    /// The byte offset in the original text just past the end of the word
+    pub end: usize,
+}
+
+/// Splits text into normalized words. A `Database` uses the same tokenizer for the documents it
+/// indexes and for the queries it answers, so that both agree on what a word is.
+pub trait Tokenizer: Send + Sync {
+    /// Split `text` into its words, in the order they appear
+    fn tokenize(&self, text: &str) -> Vec<Token>;
+}
+
+/// The default tokenizer. It segments text into words following the Unicode word boundary rules,
+/// strips any punctuation left inside a word (so "Hamlet," and "o'er" become "hamlet" and
+/// "oer"), and folds the words to lower case.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultTokenizer;
+
+impl Tokenizer for DefaultTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        text.unicode_word_indices()
+            .filter_map(|(start, word)| {
+                let normalized: String = word
+                    .chars()
+                    .filter(|c| c.is_alphanumeric())
+                    .flat_map(char::to_lowercase)
+                    .collect();
+
+                (!normalized.is_empty()).then(|| Token {
+                    text: normalized,
+                    start,
+                    end: start + word.len(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// A tokenizer that splits on whitespace and lowercases each word, keeping any punctuation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WhitespaceTokenizer;
+
+impl Tokenizer for WhitespaceTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        text.split_whitespace()
+            .map(|word| {
+                // `word` is a subslice of `text`, so its offset is the distance between them
+                let start = word.as_ptr() as usize - text.as_ptr() as usize;
+                Token {
+                    text: word.to_lowercase(),
+                    start,
+                    end: start + word.len(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A tokenizer that reduces the words produced by another tokenizer to their English stems with
+/// the Snowball stemmer, so that "running", "runs" and "run" are all indexed as "run"
+pub struct StemmingTokenizer<T> {
+    inner: T,
+    stemmer: Stemmer,
+}
+
+impl<T: Tokenizer> StemmingTokenizer<T> {
+    /// Create a tokenizer that stems the words produced by `inner`
+    pub fn new(inner: T) -> Self {
+        StemmingTokenizer {
+            inner,
+            stemmer: Stemmer::create(Algorithm::English),
+        }
+    }
+}
+
+impl<T: Tokenizer> Tokenizer for StemmingTokenizer<T> {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        let mut tokens = self.inner.tokenize(text);
+        for token in tokens.iter_mut() {
+            token.text = self.stemmer.stem(&token.text).into_owned();
+        }
+        tokens
+    }
+}
@@ -0,0 +1,151 @@
+use crate::message::Request;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// The write-ahead log is a sequence of records, one per change made to the database. Each record
+// is the change encoded as a `Request`, framed as
+//
+//     [payload length: u64][CRC-32 of payload: u32][payload]
+//
+// with integers in big-endian order. A crash can leave the last record partially written, so on
+// replay the log is cut short at the first record that is incomplete or fails its checksum.
+// Records are read and replayed one at a time, so replaying never holds more than one record in
+// memory however large the log has grown.
+
+/// The size in bytes of the header in front of every record
+const HEADER_LEN: usize = 12;
+
+/// An append-only log of the changes made to a database
+pub struct WriteAheadLog {
+    file: File,
+    /// The length of the log up to the end of its last complete record
+    len: u64,
+}
+
+impl WriteAheadLog {
+    /// Open the log at `path`, creating it if it does not exist, and call `replay` with each
+    /// change recorded in it after the first `start` bytes, oldest first. `start` must be the end
+    /// of a record, as returned by `size`. A torn record at the end of the log is discarded.
+    /// Opening fails with the first error `replay` returns, or with an `InvalidData` error if a
+    /// complete record holds a change that cannot be decoded or the log is shorter than `start`,
+    /// leaving the log as it is.
+    pub fn open<F>(path: &Path, start: u64, mut replay: F) -> io::Result<WriteAheadLog>
+    where
+        F: FnMut(Request) -> io::Result<()>,
+    {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        if len < start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is shorter than its checkpoint", path.display()),
+            ));
+        }
+
+        // The offset just past the last complete record
+        let mut offset = start;
+        file.seek(SeekFrom::Start(start))?;
+        let mut reader = BufReader::new(&file);
+        while let Some((request, record_len)) = read_record(&mut reader, len - offset)? {
+            replay(request)?;
+            offset += record_len;
+        }
+
+        if offset < len {
+            eprintln!(
+                "Discarding {} bytes of torn records at the end of {}",
+                len - offset,
+                path.display()
+            );
+            file.set_len(offset)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+
+        Ok(WriteAheadLog { file, len: offset })
+    }
+
+    /// The length of the log in bytes, which is the end of its last record
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// Durably append `request` to the log. The record is flushed to disk before returning. If
+    /// that fails, whatever part of the record was written is cut off again, so that the change
+    /// is not replayed and later records are not hidden behind a torn one.
+    pub fn append(&mut self, request: &Request) -> io::Result<()> {
+        let payload = request.to_bytes();
+
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+        record.extend((payload.len() as u64).to_be_bytes());
+        record.extend(crc32(&payload).to_be_bytes());
+        record.extend(payload);
+
+        if let Err(e) = self.file.write_all(&record).and_then(|()| self.file.sync_data()) {
+            self.file.set_len(self.len)?;
+            self.file.seek(SeekFrom::Start(self.len))?;
+            return Err(e);
+        }
+        self.len += record.len() as u64;
+        Ok(())
+    }
+}
+
+// Read the next record from `reader`, which has `remaining` bytes left, returning it with its
+// length in bytes, or `None` if the record is incomplete or fails its checksum. A complete record
+// whose change cannot be decoded, like one written by a newer version, is an `InvalidData` error. The length in the header is
+// checked against `remaining` before anything is allocated, so a corrupt length cannot make the
+// payload buffer arbitrarily large.
+fn read_record<R: Read>(reader: &mut R, remaining: u64) -> io::Result<Option<(Request, u64)>> {
+    let header_len = HEADER_LEN as u64;
+    if remaining < header_len {
+        return Ok(None);
+    }
+
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let (len, checksum) = header.split_at(8);
+    let len = u64::from_be_bytes(len.try_into().unwrap());
+    let checksum = u32::from_be_bytes(checksum.try_into().unwrap());
+    if len > remaining - header_len {
+        return Ok(None);
+    }
+
+    let Ok(payload_len) = usize::try_from(len) else {
+        return Ok(None);
+    };
+    let mut payload = vec![0; payload_len];
+    reader.read_exact(&mut payload)?;
+    if crc32(&payload) != checksum {
+        return Ok(None);
+    }
+
+    // A record that passed its checksum was written in full, so failing to decode it is not a
+    // torn write, and cutting it off would lose it and every record after it
+    match Request::from_bytes(&payload[..]) {
+        Some(request) => Ok(Some((request, header_len + len))),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "a log record holds a change that cannot be decoded",
+        )),
+    }
+}
+
+// The CRC-32 (IEEE) checksum of `bytes`
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
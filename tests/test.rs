@@ -16,6 +16,7 @@ mod test_multimap {
         quickcheck(new_is_empty as fn(i32));
     }
     #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn test_get_after_set_single_5() {
         fn get_after_set_single(k: i32, v: usize) {
             let map = ConcurrentMultiMap::<UnCloneable, usize>::new(10);
@@ -25,6 +26,7 @@ mod test_multimap {
         quickcheck(get_after_set_single as fn(i32, usize));
     }
     #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn test_get_after_set_multi_5() {
         use std::collections::HashSet;
         fn get_after_set_multi(k: i32, values: HashSet<usize>) {
@@ -45,6 +47,7 @@ mod test_multimap {
         quickcheck(get_after_set_multi as fn(i32, HashSet<usize>));
     }
     #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn test_get_from_large_map_5() {
         fn get_from_large_map(k: i32, v: usize, others: Vec<(i32, usize)>) {
             let map = ConcurrentMultiMap::<UnCloneable, usize>::new(1000);
@@ -57,6 +60,7 @@ mod test_multimap {
         quickcheck(get_from_large_map as fn(i32, usize, Vec<(i32, usize)>));
     }
     #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn test_no_duplicates_5() {
         fn no_duplicates(k: i32, v: usize) {
             let map = ConcurrentMultiMap::<UnCloneable, usize>::new(10);
@@ -69,6 +73,30 @@ mod test_multimap {
         quickcheck(no_duplicates as fn(i32, usize));
     }
     #[test]
+    fn test_remove_5() {
+        use std::collections::HashSet;
+        fn remove(k: i32, values: HashSet<usize>, removed: usize) {
+            let map = ConcurrentMultiMap::<UnCloneable, usize>::new(10);
+            for value in values.iter() {
+                map.set(UnCloneable(k), *value);
+            }
+            assert_eq!(
+                map.remove(&UnCloneable(k), &removed),
+                values.contains(&removed)
+            );
+            assert!(!map.remove(&UnCloneable(k), &removed));
+
+            let result = map.get(&UnCloneable(k));
+            assert!(!result.contains(&removed));
+            assert_eq!(
+                result.len(),
+                values.iter().filter(|v| **v != removed).count()
+            );
+        }
+        quickcheck(remove as fn(i32, HashSet<usize>, usize));
+    }
+    #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn passes_stress_test_10() {
         fn passes_stress_test(tuples: Vec<(i32, usize, bool)>) {
             use std::sync::Arc;
@@ -79,22 +107,1804 @@ mod test_multimap {
             }
             let tuples_chunked = tuples.chunks(chunk_size).map(Vec::from).collect::<Vec<_>>();
 
-            let map = Arc::new(ConcurrentMultiMap::<UnCloneable, usize>::new(128));
-            let threads = tuples_chunked.into_iter().map(|chunk| {
-                let map = Arc::clone(&map);
-                std::thread::spawn(move || {
-                    for (k, v, is_write) in chunk.iter() {
-                        if *is_write {
-                            map.set(UnCloneable(*k), *v as usize);
-                        } else {
-                            map.get(&UnCloneable(*k));
-                        }
-                    }
-                })
-            });
-            threads.into_iter().for_each(|t| t.join().unwrap());
+            let map = Arc::new(ConcurrentMultiMap::<UnCloneable, usize>::new(128));
+            let threads = tuples_chunked.into_iter().map(|chunk| {
+                let map = Arc::clone(&map);
+                std::thread::spawn(move || {
+                    for (k, v, is_write) in chunk.iter() {
+                        if *is_write {
+                            map.set(UnCloneable(*k), *v as usize);
+                        } else {
+                            map.get(&UnCloneable(*k));
+                        }
+                    }
+                })
+            });
+            threads.into_iter().for_each(|t| t.join().unwrap());
+        }
+        quickcheck(passes_stress_test as fn(Vec<(i32, usize, bool)>));
+    }
+}
+
+// ============================ POSTING ============================
+mod test_posting {
+    use super::*;
+    use ngram::database::*;
+    use ngram::posting::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_posting_list_5() {
+        fn posting_list(operations: Vec<(u16, Vec<u16>, bool)>) {
+            let mut list = PostingList::new();
+            let mut model = BTreeMap::new();
+            for (doc, positions, is_insert) in operations {
+                let doc = doc as usize;
+                if is_insert {
+                    let mut positions: Vec<usize> =
+                        positions.into_iter().map(usize::from).collect();
+                    positions.sort();
+                    positions.dedup();
+                    list.insert(Posting {
+                        doc,
+                        positions: positions.clone(),
+                    });
+                    model.insert(doc, positions);
+                } else {
+                    assert_eq!(list.remove(doc), model.remove(&doc).is_some());
+                }
+            }
+
+            let expected: Vec<Posting> = model
+                .into_iter()
+                .map(|(doc, positions)| Posting { doc, positions })
+                .collect();
+            assert_eq!(list.iter().collect::<Vec<_>>(), expected);
+            assert_eq!(list.len(), expected.len());
+        }
+        quickcheck(posting_list as fn(Vec<(u16, Vec<u16>, bool)>));
+    }
+
+    #[test]
+    fn test_posting_table_stats_5() {
+        let database = Database::default();
+        let text = std::fs::read_to_string("data/carroll-alice.txt").unwrap();
+        for paragraph in text.split("\n\n") {
+            database.publish(paragraph.to_string());
+        }
+
+        let stats = database.index_stats();
+        assert!(stats.memory.keys > 0);
+        assert!(stats.memory.postings >= stats.memory.keys);
+        assert!(stats.memory.compressed_bytes * 2 < stats.memory.uncompressed_bytes);
+        assert_eq!(stats.segments, 0);
+    }
+}
+
+// ============================ CONTENT ============================
+mod test_content {
+    use super::*;
+    use ngram::content::*;
+    use ngram::database::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_content_store_5() {
+        fn content_store(texts: Vec<String>, replacement: String) {
+            for store in [ContentStore::temporary().unwrap(), ContentStore::memory()] {
+                check_store(store, &texts, &replacement);
+            }
+        }
+        fn check_store(store: ContentStore, texts: &[String], replacement: &str) {
+            for (id, text) in texts.iter().enumerate() {
+                assert_eq!(store.edit().push(text).unwrap(), id);
+            }
+            assert_eq!(store.edit().push_deleted(), texts.len());
+            for (id, text) in texts.iter().enumerate() {
+                assert_eq!(store.get(id).unwrap().as_ref(), Some(text));
+            }
+            assert_eq!(store.get(texts.len()).unwrap(), None);
+
+            if !texts.is_empty() {
+                let mut editor = store.edit();
+                let staged = editor.write(replacement).unwrap();
+                assert_eq!(store.get(0).unwrap().as_ref(), texts.first());
+                editor.set(0, Some(staged));
+                drop(editor);
+                assert_eq!(store.get(0).unwrap().as_deref(), Some(replacement));
+                store.edit().set(0, None);
+                assert_eq!(store.get(0).unwrap(), None);
+            }
+            // Ids that were never pushed are ignored
+            store.edit().set(texts.len() + 1, None);
+            assert_eq!(store.get(texts.len() + 1).unwrap(), None);
+        }
+        quickcheck(content_store as fn(Vec<String>, String));
+    }
+
+    #[test]
+    fn test_retrieve_while_publishing_5() {
+        let database = Arc::new(Database::default());
+        let text = std::fs::read_to_string("data/carroll-alice.txt").unwrap();
+        let first = database.publish(text.clone());
+
+        let publisher = {
+            let database = Arc::clone(&database);
+            let text = text.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    database.publish(text.clone());
+                }
+            })
+        };
+        for _ in 0..50 {
+            assert_eq!(database.retrieve(first).as_ref(), Some(&text));
+        }
+        publisher.join().unwrap();
+
+        assert_eq!(database.retrieve(50).as_ref(), Some(&text));
+        assert_eq!(database.retrieve(51), None);
+    }
+}
+
+// ============================ DATABASE ============================
+mod test_database {
+    use ngram::database::*;
+    #[test]
+    fn test_search_phrase_5() {
+        let database = Database::default();
+        let id1 = database.publish("to be or not to be".to_string());
+        let id2 = database.publish("not to be outdone".to_string());
+        let _id3 = database.publish("be not afraid to speak".to_string());
+
+        let mut ids = database.search("to be");
+        ids.sort();
+        assert_eq!(ids, vec![id1, id2]);
+        assert_eq!(database.search("to be or not"), vec![id1]);
+        assert_eq!(database.search("NOT TO BE"), vec![id1, id2]);
+        assert_eq!(database.search("be to"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_search_phrase_across_documents_5() {
+        let database = Database::default();
+        let id1 = database.publish("the quick brown fox".to_string());
+        let _id2 = database.publish("a brown dog and a quick cat".to_string());
+
+        assert_eq!(database.search("quick brown"), vec![id1]);
+        assert_eq!(database.search("brown cat"), Vec::<usize>::new());
+        assert_eq!(database.search(""), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_search_ngram_config_5() {
+        let docs = [
+            "the quick brown fox jumps over the lazy dog",
+            "a lazy brown dog sleeps",
+            "the fox jumps",
+        ];
+        let unigrams = Database::default();
+        let trigrams = Database::new(DatabaseConfig {
+            max_ngram: 3,
+            ..DatabaseConfig::default()
+        });
+        for doc in docs {
+            unigrams.publish(doc.to_string());
+            trigrams.publish(doc.to_string());
+        }
+
+        for phrase in [
+            "fox jumps",
+            "the lazy dog",
+            "quick brown fox jumps over",
+            "lazy brown",
+        ] {
+            let mut expected = unigrams.search(phrase);
+            let mut actual = trigrams.search(phrase);
+            expected.sort();
+            actual.sort();
+            assert_eq!(actual, expected, "phrase {:?}", phrase);
+        }
+        assert_eq!(trigrams.search("brown fox jumps"), vec![0]);
+    }
+
+    #[test]
+    fn test_delete_5() {
+        let database = Database::default();
+        let a = database.publish("the ghost of hamlet".to_string());
+        let b = database.publish("the ghost of banquo".to_string());
+        let c = database.publish("hamlet alone".to_string());
+
+        assert!(database.delete(b).unwrap());
+        assert!(!database.delete(b).unwrap());
+        assert!(!database.delete(42).unwrap());
+
+        assert_eq!(database.retrieve(b), None);
+        assert_eq!(database.retrieve(c), Some("hamlet alone".to_string()));
+        assert_eq!(database.search("ghost"), vec![a]);
+        assert_eq!(database.search("banquo"), Vec::<usize>::new());
+        assert_eq!(database.query("NOT hamlet"), Ok(vec![]));
+        assert_eq!(database.fuzzy_search("banquo", 0.5), Vec::<usize>::new());
+
+        let d = database.publish("banquo returns".to_string());
+        assert_eq!(d, 3);
+        assert_eq!(database.search("banquo"), vec![d]);
+    }
+
+    #[test]
+    fn test_update_5() {
+        let database = Database::default();
+        let a = database.publish("the ghost of hamlet".to_string());
+        let b = database.publish("the ghost of banquo".to_string());
+
+        assert!(database
+            .update(a, "hamlet sees a ghost".to_string())
+            .unwrap());
+        assert!(!database.update(7, "nothing here".to_string()).unwrap());
+
+        assert_eq!(
+            database.retrieve(a),
+            Some("hamlet sees a ghost".to_string())
+        );
+        assert_eq!(database.query("ghost"), Ok(vec![a, b]));
+        assert_eq!(database.search("the ghost"), vec![b]);
+        assert_eq!(database.search("sees"), vec![a]);
+        assert_eq!(database.search("of hamlet"), Vec::<usize>::new());
+
+        assert!(database.delete(b).unwrap());
+        assert!(!database.update(b, "revived".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_fuzzy_search_5() {
+        let database = Database::default();
+        let emma = std::fs::read_to_string("data/austen-emma.txt").unwrap();
+        let macbeth = std::fs::read_to_string("data/shakespeare-macbeth.txt").unwrap();
+        let _emma = database.publish(emma);
+        let macbeth = database.publish(macbeth);
+
+        assert_eq!(database.search("machbeth"), Vec::<usize>::new());
+        assert_eq!(
+            database.fuzzy_search("machbeth", 0.6).first(),
+            Some(&macbeth)
+        );
+        assert_eq!(database.fuzzy_search("acbet", 0.6), vec![macbeth]);
+        assert_eq!(database.fuzzy_search("zzzzzz", 0.5), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_deduplicate_5() {
+        let database = Database::new(DatabaseConfig {
+            deduplicate: true,
+            ..DatabaseConfig::default()
+        });
+        let a = database.publish("the ghost of hamlet".to_string());
+        assert_eq!(
+            database
+                .publish_with_status("the ghost of hamlet".to_string())
+                .unwrap(),
+            (a, true)
+        );
+        assert_eq!(database.search("ghost"), vec![a]);
+
+        // Documents only count as duplicates of what they currently hold
+        database.update(a, "hamlet alone".to_string()).unwrap();
+        let b = database.publish("the ghost of hamlet".to_string());
+        assert_ne!(a, b);
+        assert_eq!(
+            database
+                .publish_with_status("hamlet alone".to_string())
+                .unwrap(),
+            (a, true)
+        );
+        database.delete(a).unwrap();
+        assert_eq!(
+            database
+                .publish_with_status("hamlet alone".to_string())
+                .unwrap(),
+            (b + 1, false)
+        );
+
+        let database = Database::default();
+        let a = database.publish("the ghost of hamlet".to_string());
+        assert_eq!(
+            database
+                .publish_with_status("the ghost of hamlet".to_string())
+                .unwrap(),
+            (a + 1, false)
+        );
+    }
+
+    #[test]
+    fn test_metadata_from_header_5() {
+        use ngram::metadata::Metadata;
+        let header = |path: &str| Metadata::from_header(&std::fs::read_to_string(path).unwrap());
+        assert_eq!(
+            header("data/austen-emma.txt"),
+            Some(Metadata {
+                title: Some("Emma".to_string()),
+                author: Some("Jane Austen".to_string()),
+                year: Some(1816),
+                tags: vec![],
+            })
+        );
+        assert_eq!(
+            header("data/edgeworth-parents.txt"),
+            Some(Metadata {
+                title: Some("The Parent's Assistant".to_string()),
+                author: Some("Maria Edgeworth".to_string()),
+                year: None,
+                tags: vec![],
+            })
+        );
+        assert_eq!(
+            header("data/bible-kjv.txt").unwrap().fields(),
+            vec![("title", "The King James Bible".to_string())]
+        );
+        assert_eq!(Metadata::from_header("no header"), None);
+    }
+
+    #[test]
+    fn test_field_search_5() {
+        use ngram::metadata::Metadata;
+        let database = Database::default();
+        let publish = |path: &str, tags: &[&str]| {
+            let doc = std::fs::read_to_string(path).unwrap();
+            let mut metadata = Metadata::from_header(&doc).unwrap();
+            metadata.tags = tags.iter().map(|tag| tag.to_string()).collect();
+            database.publish_with_metadata(doc, metadata).unwrap().0
+        };
+        let emma = publish("data/austen-emma.txt", &["novel"]);
+        let persuasion = publish("data/austen-persuasion.txt", &["novel", "romance"]);
+        let macbeth = publish("data/shakespeare-macbeth.txt", &["play", "tragedy"]);
+        let bible = publish("data/bible-kjv.txt", &[]);
+
+        assert_eq!(database.query("author:austen"), Ok(vec![emma, persuasion]));
+        assert_eq!(
+            database.query("author:\"jane austen\""),
+            Ok(vec![emma, persuasion])
+        );
+        assert_eq!(database.query("author:\"austen jane\""), Ok(vec![]));
+        assert_eq!(
+            database.query("year:1818 OR tag:play"),
+            Ok(vec![persuasion, macbeth])
+        );
+        assert_eq!(database.query("title:bible"), Ok(vec![bible]));
+        assert_eq!(database.query("tag:\"novel romance\""), Ok(vec![]));
+        assert_eq!(database.query("tag:novel NOT author:austen"), Ok(vec![]));
+
+        // Fields are searched separately from the text
+        assert_eq!(database.search("author:austen"), Vec::<usize>::new());
+        assert_eq!(
+            database.metadata(macbeth).unwrap().title.as_deref(),
+            Some("The Tragedie of Macbeth")
+        );
+        assert_eq!(database.metadata(42), None);
+
+        database.delete(persuasion).unwrap();
+        assert_eq!(database.query("author:austen"), Ok(vec![emma]));
+        assert_eq!(database.metadata(persuasion), None);
+        database
+            .update(emma, "a different text".to_string())
+            .unwrap();
+        assert_eq!(database.query("author:austen"), Ok(vec![emma]));
+    }
+
+    #[test]
+    fn test_field_key_space_5() {
+        use ngram::metadata::Metadata;
+        use ngram::tokenizer::WhitespaceTokenizer;
+        use std::sync::Arc;
+        let database = Database::new(DatabaseConfig {
+            tokenizer: Arc::new(WhitespaceTokenizer),
+            ..DatabaseConfig::default()
+        });
+        let text = database.publish("author:austen author\0austen".to_string());
+        let metadata = Metadata {
+            author: Some("austen".to_string()),
+            ..Metadata::default()
+        };
+        let field = database
+            .publish_with_metadata("emma".to_string(), metadata)
+            .unwrap()
+            .0;
+
+        // Words of the text never reach the keys of metadata fields, whatever the tokenizer
+        assert_eq!(database.query("author:austen"), Ok(vec![field]));
+        assert_eq!(database.search("author:austen"), vec![text]);
+    }
+
+    #[test]
+    fn test_snippet_5() {
+        use ngram::snippet::Snippet;
+        let text = "one two three\nfour five";
+        let snippet = Snippet::new(text, &[(4, 7), (14, 18)]);
+        assert_eq!(snippet.text, "one two three four five");
+        assert_eq!(snippet.matches, vec![(4, 7), (14, 18)]);
+        assert_eq!(snippet.highlight("<", ">"), "one <two> three <four> five");
+        assert_eq!(Snippet::new(text, &[]).matches, vec![]);
+
+        // Long texts are cut to whole words around the first match
+        let text = "word ".repeat(100) + "needle" + &" word".repeat(100) + " needle";
+        let start = text.find("needle").unwrap();
+        let snippet = Snippet::new(&text, &[(start, start + 6), (text.len() - 6, text.len())]);
+        assert!(snippet.text.len() <= 6 + 2 * ngram::snippet::CONTEXT);
+        assert!(snippet.text.starts_with("word ") && snippet.text.ends_with(" word"));
+        assert_eq!(snippet.highlight("<", ">").matches("<needle>").count(), 1);
+    }
+
+    #[test]
+    fn test_search_with_snippets_5() {
+        let database = Database::default();
+        let hamlet = database.publish(
+            "Enter the Ghost.\nHoratio: Stay, illusion!\nIf thou hast any sound, speak to me."
+                .to_string(),
+        );
+        let other = database.publish("the ghost of christmas past".to_string());
+        let (json, _) = database
+            .publish_json(r#"{"title": "Macbeth", "line": "a ghost appears"}"#.to_string())
+            .unwrap();
+
+        let (total, hits) = database
+            .search_with_snippets("ghost NOT christmas", 0, None)
+            .unwrap();
+        assert_eq!(total, 2);
+        let ids: Vec<usize> = hits.iter().map(|hit| hit.id).collect();
+        assert_eq!(ids, vec![hamlet, json]);
+        assert_eq!(
+            hits[0].snippet.highlight("[", "]"),
+            "Enter the [Ghost]. Horatio: Stay, illusion! If thou hast any sound, speak to me."
+        );
+        assert_eq!(
+            hits[1].snippet.highlight("[", "]"),
+            "a [ghost] appears Macbeth"
+        );
+
+        let (total, hits) = database
+            .search_with_snippets("\"the ghost\" OR past", 1, Some(1))
+            .unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].id, other);
+        assert_eq!(
+            hits[0].snippet.highlight("[", "]"),
+            "[the ghost] of christmas [past]"
+        );
+
+        // Documents matched only by a field are shown from the start
+        let (_, hits) = database
+            .search_with_snippets("title:macbeth", 0, None)
+            .unwrap();
+        assert_eq!(hits[0].snippet.matches, vec![]);
+        assert_eq!(hits[0].snippet.text, "a ghost appears Macbeth");
+        assert!(database.search_with_snippets("(ghost", 0, None).is_err());
+    }
+
+    #[test]
+    fn test_query_page_5() {
+        let database = Database::default();
+        for i in 0..10 {
+            database.publish(format!("ghost number {}", i));
+        }
+        database.publish("no match".to_string());
+
+        assert_eq!(
+            database.query_page("ghost", 0, None),
+            Ok((10, (0..10).collect()))
+        );
+        assert_eq!(
+            database.query_page("ghost", 0, Some(4)),
+            Ok((10, vec![0, 1, 2, 3]))
+        );
+        assert_eq!(
+            database.query_page("ghost", 8, Some(4)),
+            Ok((10, vec![8, 9]))
+        );
+        assert_eq!(database.query_page("ghost", 20, Some(4)), Ok((10, vec![])));
+        assert_eq!(database.query_page("ghost", 0, Some(0)), Ok((10, vec![])));
+
+        // New documents only show up on later pages
+        let first = database.query_page("ghost", 0, Some(5)).unwrap();
+        database.publish("another ghost".to_string());
+        assert_eq!(database.query_page("ghost", 0, Some(5)).unwrap().1, first.1);
+        assert_eq!(
+            database.query_page("ghost", 10, Some(5)),
+            Ok((11, vec![11]))
+        );
+        assert!(database.query_page("NOT", 0, None).is_err());
+    }
+
+    #[test]
+    fn test_publish_json_5() {
+        let database = Database::default();
+        let record = r#"{"title": "Hamlet", "author": {"name": "William Shakespeare"},
+            "year": 1599, "characters": ["Horatio", "the Ghost"]}"#;
+        let (hamlet, _) = database.publish_json(record.to_string()).unwrap();
+        let other = database.publish("the ghost of William Shakespeare".to_string());
+
+        assert_eq!(database.retrieve(hamlet).as_deref(), Some(record));
+        assert_eq!(database.query("title:hamlet"), Ok(vec![hamlet]));
+        assert_eq!(database.query("author.name:shakespeare"), Ok(vec![hamlet]));
+        assert_eq!(database.query("characters:ghost"), Ok(vec![hamlet]));
+        assert_eq!(database.query("characters:\"horatio the\""), Ok(vec![]));
+        assert_eq!(database.query("year:1599"), Ok(vec![]));
+        assert_eq!(database.search("ghost"), vec![hamlet, other]);
+        assert_eq!(database.search("characters"), Vec::<usize>::new());
+        assert_eq!(database.metadata(hamlet), Some(Default::default()));
+
+        assert!(database
+            .publish_json("[\"not an object\"]".to_string())
+            .is_err());
+        assert!(database.publish_json("{\"title\": ".to_string()).is_err());
+
+        database
+            .update(hamlet, "a different text".to_string())
+            .unwrap();
+        assert_eq!(database.query("title:hamlet"), Ok(vec![]));
+        assert_eq!(database.search("ghost"), vec![other]);
+
+        let (id, _) = database.publish_json(record.to_string()).unwrap();
+        database.delete(id).unwrap();
+        assert_eq!(database.query("title:hamlet OR ghost"), Ok(vec![other]));
+    }
+
+    #[test]
+    fn test_json_quoted_field_names_5() {
+        let database = Database::default();
+        let record = r#"{"first-name": "Jane", "full name": "Jane Austen", "1816": "Emma"}"#;
+        let (jane, _) = database.publish_json(record.to_string()).unwrap();
+
+        assert_eq!(database.query("\"first-name\":jane"), Ok(vec![jane]));
+        assert_eq!(
+            database.query("\"full name\":\"jane austen\""),
+            Ok(vec![jane])
+        );
+        assert_eq!(database.query("\"1816\":emma"), Ok(vec![jane]));
+        assert_eq!(database.query("\"first-name\":austen"), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_edit_while_publishing_5() {
+        use std::sync::Arc;
+        const DOCS: usize = 200;
+        let database = Arc::new(Database::default());
+
+        let publisher = {
+            let database = Arc::clone(&database);
+            std::thread::spawn(move || {
+                for i in 0..DOCS {
+                    database.publish(format!("ghost{} walks", i));
+                }
+            })
+        };
+
+        // Edit every document as soon as it can be retrieved, which may be before it has been
+        // indexed
+        for id in 0..DOCS {
+            while database.retrieve(id).is_none() {
+                std::thread::yield_now();
+            }
+            if id % 2 == 0 {
+                assert!(database.delete(id).unwrap());
+            } else {
+                assert!(database.update(id, "a quiet night".to_string()).unwrap());
+            }
+        }
+        publisher.join().unwrap();
+
+        assert_eq!(database.search("walks"), Vec::<usize>::new());
+        assert_eq!(database.search("ghost1"), Vec::<usize>::new());
+        assert_eq!(database.search("quiet").len(), DOCS / 2);
+        assert_eq!(
+            database.top_ngrams(1, 10),
+            Ok(vec![
+                ("a".to_string(), DOCS / 2),
+                ("night".to_string(), DOCS / 2),
+                ("quiet".to_string(), DOCS / 2)
+            ])
+        );
+    }
+}
+
+// ============================ NGRAM STATS ============================
+mod test_ngram_stats {
+    use ngram::counts::NgramStats;
+    use ngram::database::*;
+    use ngram::query::QueryError;
+    #[test]
+    fn test_ngram_stats_5() {
+        let database = Database::new(DatabaseConfig {
+            max_ngram: 2,
+            ..Default::default()
+        });
+        database.publish("to be or not to be".to_string());
+        database.publish("not to be outdone".to_string());
+        database.publish("be not afraid to speak".to_string());
+
+        assert_eq!(
+            database.ngram_stats("To Be"),
+            Ok(NgramStats {
+                total: 3,
+                documents: 2,
+                counts: vec![(0, 2), (1, 1)],
+            })
+        );
+        // Longer phrases than the n-gram size are counted too
+        assert_eq!(
+            database.ngram_stats("be or not"),
+            Ok(NgramStats {
+                total: 1,
+                documents: 1,
+                counts: vec![(0, 1)],
+            })
+        );
+        assert_eq!(database.ngram_stats("missing"), Ok(NgramStats::default()));
+        assert_eq!(database.ngram_stats(""), Ok(NgramStats::default()));
+
+        assert_eq!(
+            database.top_ngrams(1, 3),
+            Ok(vec![
+                ("be".to_string(), 4),
+                ("to".to_string(), 4),
+                ("not".to_string(), 3)
+            ])
+        );
+        assert_eq!(
+            database.top_ngrams(2, 2),
+            Ok(vec![("to be".to_string(), 3), ("not to".to_string(), 2)])
+        );
+        assert_eq!(database.top_ngrams(2, 0), Ok(vec![]));
+        assert_eq!(
+            database.top_ngrams(3, 1),
+            Err(QueryError::NgramSize { n: 3, max_ngram: 2 })
+        );
+        assert_eq!(
+            database.top_ngrams(0, 1),
+            Err(QueryError::NgramSize { n: 0, max_ngram: 2 })
+        );
+
+        database.update(1, "outdone again".to_string()).unwrap();
+        assert_eq!(database.ngram_stats("to be").unwrap().total, 2);
+        assert_eq!(
+            database.top_ngrams(1, 2),
+            Ok(vec![("be".to_string(), 3), ("to".to_string(), 3)])
+        );
+
+        database.delete(0).unwrap();
+        assert_eq!(
+            database.top_ngrams(2, 1),
+            Ok(vec![("afraid to".to_string(), 1)])
+        );
+        let words: Vec<String> = database
+            .top_ngrams(1, 10)
+            .unwrap()
+            .into_iter()
+            .map(|(word, count)| {
+                assert_eq!(count, 1);
+                word
+            })
+            .collect();
+        assert_eq!(
+            words,
+            ["afraid", "again", "be", "not", "outdone", "speak", "to"]
+        );
+    }
+
+    #[test]
+    fn test_top_ngrams_across_segments_5() {
+        let dir = std::env::temp_dir().join(format!("ngram-top-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = DatabaseConfig {
+            max_ngram: 2,
+            flush_threshold: 4,
+            ..Default::default()
+        };
+        let database = Database::open(config, &dir).unwrap();
+        for doc in [
+            "to be or not to be",
+            "not to be outdone",
+            "be not afraid to speak",
+        ] {
+            database.publish(doc.to_string());
+        }
+        database.update(1, "outdone again".to_string()).unwrap();
+        assert!(database.index_stats().segments > 0);
+
+        // Counts come from the postings, wherever they are stored
+        assert_eq!(
+            database.top_ngrams(1, 2),
+            Ok(vec![("be".to_string(), 3), ("to".to_string(), 3)])
+        );
+        database.delete(0).unwrap();
+        assert_eq!(
+            database.top_ngrams(2, 1),
+            Ok(vec![("afraid to".to_string(), 1)])
+        );
+        drop(database);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ngram_stats_stop_words_5() {
+        let database = Database::new(DatabaseConfig {
+            max_ngram: 2,
+            stop_words: ["the".to_string()].into_iter().collect(),
+            ..Default::default()
+        });
+        database.publish("the ghost of the king".to_string());
+        database.publish("the king is dead".to_string());
+
+        assert_eq!(
+            database.ngram_stats("the"),
+            Err(QueryError::StopWord("the".to_string()))
+        );
+        assert_eq!(
+            database.ngram_stats("the king").unwrap().counts,
+            vec![(0, 1), (1, 1)]
+        );
+        assert_eq!(database.top_ngrams(1, 1), Ok(vec![("king".to_string(), 2)]));
+        assert!(database
+            .top_ngrams(2, 10)
+            .unwrap()
+            .iter()
+            .all(|(ngram, _)| !ngram.split(' ').any(|word| word == "the")));
+    }
+}
+
+// ============================ DURABILITY ============================
+mod test_durability {
+    use ngram::database::*;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ngram-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_replay_write_ahead_log_5() {
+        let dir = data_dir("replay");
+        {
+            let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            database.publish("the ghost of hamlet".to_string());
+            database.publish("the ghost of banquo".to_string());
+            database.publish("horatio".to_string());
+            database.update(0, "hamlet alone".to_string()).unwrap();
+            database.delete(1).unwrap();
+        }
+
+        let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+        assert_eq!(database.retrieve(0), Some("hamlet alone".to_string()));
+        assert_eq!(database.retrieve(1), None);
+        assert_eq!(database.retrieve(2), Some("horatio".to_string()));
+        assert_eq!(database.search("ghost"), Vec::<usize>::new());
+        assert_eq!(database.publish("new".to_string()), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_torn_record_5() {
+        let dir = data_dir("torn");
+        {
+            let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            database.publish("first".to_string());
+            database.publish("second".to_string());
+        }
+
+        // Simulate a crash in the middle of writing a third record
+        let wal = dir.join("wal.log");
+        let mut file = OpenOptions::new().append(true).open(wal).unwrap();
+        file.write_all(&[0, 0, 0, 0, 0, 0, 0, 100, 1, 2, 3, 4, 1, 0])
+            .unwrap();
+        drop(file);
+
+        {
+            let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            assert_eq!(database.retrieve(1), Some("second".to_string()));
+            assert_eq!(database.retrieve(2), None);
+            assert_eq!(database.publish("third".to_string()), 2);
+        }
+
+        let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+        assert_eq!(database.retrieve(2), Some("third".to_string()));
+        assert_eq!(database.search("third"), vec![2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_corrupt_length_5() {
+        let dir = data_dir("corrupt-length");
+        {
+            let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            database.publish("first".to_string());
+        }
+
+        // A record claiming to be far larger than the log is discarded without reading it
+        let wal = dir.join("wal.log");
+        let mut file = OpenOptions::new().append(true).open(&wal).unwrap();
+        let mut header = u64::MAX.to_be_bytes().to_vec();
+        header.extend([0; 4]);
+        file.write_all(&header).unwrap();
+        let len = file.metadata().unwrap().len();
+        drop(file);
+
+        let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+        assert_eq!(database.retrieve(0), Some("first".to_string()));
+        assert_eq!(database.retrieve(1), None);
+        assert_eq!(fs::metadata(&wal).unwrap().len(), len - 12);
+        drop(database);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_undecodable_record_5() {
+        let dir = data_dir("undecodable");
+        {
+            let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            database.publish("first".to_string());
+        }
+
+        // A complete record with an unknown tag, as a newer version might write, is not torn
+        let wal = dir.join("wal.log");
+        let mut file = OpenOptions::new().append(true).open(&wal).unwrap();
+        let mut record = 1u64.to_be_bytes().to_vec();
+        record.extend(0xFF00_0000u32.to_be_bytes());
+        record.push(0xFF);
+        file.write_all(&record).unwrap();
+        let len = file.metadata().unwrap().len();
+        drop(file);
+
+        let error = Database::open(DatabaseConfig::default(), &dir)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(&wal).unwrap().len(), len);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_unlogged_change_is_not_made_5() {
+        let dir = data_dir("unlogged");
+        fs::create_dir_all(&dir).unwrap();
+        // Every write to /dev/full fails as if the disk were full
+        std::os::unix::fs::symlink("/dev/full", dir.join("wal.log")).unwrap();
+
+        let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+        assert!(database.publish_with_status("hamlet".to_string()).is_err());
+        assert!(database
+            .publish_json(r#"{"title": "Hamlet"}"#.to_string())
+            .is_err());
+        assert_eq!(database.retrieve(0), None);
+        assert_eq!(database.search("hamlet"), Vec::<usize>::new());
+        assert_eq!(database.top_ngrams(1, 10), Ok(vec![]));
+        assert!(!database.update(0, "horatio".to_string()).unwrap());
+        drop(database);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_failed_content_write_5() {
+        let dir = data_dir("content-full");
+        fs::create_dir_all(&dir).unwrap();
+        std::os::unix::fs::symlink("/dev/full", dir.join("content.dat")).unwrap();
+
+        // Failing to store a document fails the publish, but not every later change
+        let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+        for _ in 0..2 {
+            assert!(database.publish_with_status("hamlet".to_string()).is_err());
+        }
+        assert_eq!(database.retrieve(0), None);
+        assert!(!database.delete(0).unwrap());
+        drop(database);
+
+        // Nothing was logged either
+        fs::remove_file(dir.join("content.dat")).unwrap();
+        let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+        assert_eq!(database.publish("horatio".to_string()), 0);
+        drop(database);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_without_deduplication_5() {
+        let dir = data_dir("deduplicate");
+        {
+            let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            database.publish("hamlet".to_string());
+            database.publish("hamlet".to_string());
+            database.publish("horatio".to_string());
+        }
+
+        // Turning deduplication on must not change the ids of documents published before
+        let config = DatabaseConfig {
+            deduplicate: true,
+            ..DatabaseConfig::default()
+        };
+        let database = Database::open(config, &dir).unwrap();
+        assert_eq!(database.retrieve(2), Some("horatio".to_string()));
+        assert_eq!(
+            database.publish_with_status("hamlet".to_string()).unwrap(),
+            (0, true)
+        );
+        assert_eq!(
+            database.publish_with_status("ophelia".to_string()).unwrap(),
+            (3, false)
+        );
+        drop(database);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_segmented_index_10() {
+        let dir = data_dir("segments");
+        let config = DatabaseConfig {
+            flush_threshold: 5,
+            ..DatabaseConfig::default()
+        };
+        let documents = [
+            "the ghost of hamlet's father",
+            "hamlet and horatio",
+            "the ghost of banquo",
+            "macbeth and banquo",
+            "horatio sees the ghost",
+            "lady macbeth",
+        ];
+
+        let memory = Database::default();
+        let queries = [
+            "ghost",
+            "hamlet",
+            "banquo",
+            "macbeth",
+            "horatio",
+            "the ghost",
+        ];
+        let check = |database: &Database| {
+            for query in queries {
+                assert_eq!(database.query(query), memory.query(query), "{}", query);
+            }
+        };
+
+        {
+            let database = Database::open(config.clone(), &dir).unwrap();
+            for _ in 0..4 {
+                for document in documents {
+                    database.publish(document.to_string());
+                    memory.publish(document.to_string());
+                }
+            }
+            check(&database);
+
+            // Postings already written to segments are hidden by newer ones
+            for id in [0, 7, 13] {
+                database
+                    .update(id, "only the ghost remains".to_string())
+                    .unwrap();
+                memory
+                    .update(id, "only the ghost remains".to_string())
+                    .unwrap();
+            }
+            for id in [1, 8, 20] {
+                database.delete(id).unwrap();
+                memory.delete(id).unwrap();
+            }
+            check(&database);
+
+            let segments = fs::read_dir(dir.join("segments")).unwrap().count();
+            assert!(segments > 0);
+        }
+
+        let database = Database::open(config, &dir).unwrap();
+        check(&database);
+        assert_eq!(database.retrieve(7), memory.retrieve(7));
+        drop(database);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_replays_only_the_tail_5() {
+        let dir = data_dir("checkpoint");
+        let config = DatabaseConfig {
+            flush_threshold: 4,
+            checkpoint_interval: 0,
+            ..DatabaseConfig::default()
+        };
+        let checkpointed_len = {
+            let database = Database::open(config.clone(), &dir).unwrap();
+            database.publish("the ghost of hamlet".to_string());
+            database.publish("the ghost of banquo".to_string());
+            database.publish("horatio".to_string());
+            database.delete(2).unwrap();
+            database.checkpoint().unwrap();
+            let checkpointed_len = fs::metadata(dir.join("wal.log")).unwrap().len();
+
+            database.publish("macbeth".to_string());
+            database.update(0, "hamlet alone".to_string()).unwrap();
+            database.delete(1).unwrap();
+            checkpointed_len
+        };
+
+        // Changes before the checkpoint are not replayed, so scrambling them goes unnoticed
+        let mut log = fs::read(dir.join("wal.log")).unwrap();
+        for byte in log[..checkpointed_len as usize].iter_mut() {
+            *byte = 0xFF;
+        }
+        fs::write(dir.join("wal.log"), log).unwrap();
+
+        let database = Database::open(config, &dir).unwrap();
+        assert_eq!(database.retrieve(0), Some("hamlet alone".to_string()));
+        assert_eq!(database.retrieve(1), None);
+        assert_eq!(database.retrieve(2), None);
+        assert_eq!(database.retrieve(3), Some("macbeth".to_string()));
+        assert_eq!(database.search("ghost"), Vec::<usize>::new());
+        assert_eq!(database.search("hamlet"), vec![0]);
+        assert_eq!(database.query("mac*"), Ok(vec![3]));
+        assert_eq!(database.fuzzy_search("hamlet", 0.5), vec![0]);
+        assert_eq!(database.publish("new".to_string()), 4);
+        drop(database);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_interval_5() {
+        let dir = data_dir("checkpoint-interval");
+        let config = DatabaseConfig {
+            checkpoint_interval: 3,
+            deduplicate: true,
+            ..DatabaseConfig::default()
+        };
+        {
+            let database = Database::open(config.clone(), &dir).unwrap();
+            for word in ["hamlet", "ophelia", "horatio", "laertes"] {
+                database.publish(word.to_string());
+            }
+            database
+                .publish_with_metadata(
+                    "yorick".to_string(),
+                    ngram::metadata::Metadata {
+                        author: Some("shakespeare".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            assert!(dir.join("checkpoint.dat").exists());
+        }
+
+        let database = Database::open(config, &dir).unwrap();
+        assert_eq!(database.search("laertes"), vec![3]);
+        assert_eq!(database.query("author:shakespeare"), Ok(vec![4]));
+        // The content hashes are restored from the checkpoint too
+        assert_eq!(
+            database.publish_with_status("hamlet".to_string()).unwrap(),
+            (0, true)
+        );
+        drop(database);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unusable_checkpoint_5() {
+        let dir = data_dir("unusable-checkpoint");
+        let config = DatabaseConfig {
+            checkpoint_interval: 0,
+            ..DatabaseConfig::default()
+        };
+        {
+            let database = Database::open(config.clone(), &dir).unwrap();
+            database.publish("the ghost of hamlet".to_string());
+            database.checkpoint().unwrap();
+            database.publish("the ghost of banquo".to_string());
+        }
+
+        // A checkpoint taken with another n-gram size is ignored, and the log replayed in full
+        let bigrams = DatabaseConfig {
+            max_ngram: 2,
+            ..config.clone()
+        };
+        {
+            let database = Database::open(bigrams.clone(), &dir).unwrap();
+            assert_eq!(database.search("the ghost"), vec![0, 1]);
+            assert_eq!(
+                database.top_ngrams(2, 1),
+                Ok(vec![("ghost of".to_string(), 2)])
+            );
+            assert!(!dir.join("checkpoint.dat").exists());
+            database.checkpoint().unwrap();
+        }
+
+        // So is a checkpoint whose segments are no longer listed
+        fs::remove_file(dir.join("segments").join("MANIFEST")).unwrap();
+        let database = Database::open(bigrams, &dir).unwrap();
+        assert_eq!(database.search("ghost of"), vec![0, 1]);
+        assert_eq!(
+            database.retrieve(1),
+            Some("the ghost of banquo".to_string())
+        );
+        drop(database);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_restore_5() {
+        let dir = data_dir("snapshot");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("archive.snapshot");
+
+        let database = Database::default();
+        for file in [
+            "data/shakespeare-hamlet.txt",
+            "data/shakespeare-macbeth.txt",
+        ] {
+            database.publish(fs::read_to_string(file).unwrap());
+        }
+        database.publish("the ghost of banquo".to_string());
+        database.delete(0).unwrap();
+        database.snapshot(&path).unwrap();
+
+        let restored = Database::restore(DatabaseConfig::default(), &path).unwrap();
+        for id in 0..4 {
+            assert_eq!(restored.retrieve(id), database.retrieve(id));
+        }
+        for query in [
+            "ghost",
+            "banquo OR denmark",
+            "\"thane of cawdor\"",
+            "NOT macbeth",
+        ] {
+            assert_eq!(restored.query(query), database.query(query));
+            assert_eq!(restored.ranked_search(query), database.ranked_search(query));
+        }
+        assert_eq!(restored.fuzzy_search("banqo", 0.5), vec![1, 2]);
+        assert_eq!(restored.publish("new".to_string()), 3);
+
+        fs::write(&path, b"not a snapshot").unwrap();
+        assert!(Database::restore(DatabaseConfig::default(), &path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_version_3_field_keys_5() {
+        use ngram::metadata::Metadata;
+        use ngram::snapshot::Snapshot;
+        use std::collections::{BTreeMap, BTreeSet};
+        let dir = data_dir("snapshot-v3");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("archive.snapshot");
+
+        // Version 3 snapshots joined field names and words with ':'
+        let posting = || {
+            vec![Posting {
+                doc: 0,
+                positions: vec![0],
+            }]
+        };
+        let snapshot = Snapshot {
+            version: 3,
+            documents: vec![Some(("emma".to_string(), 1))],
+            postings: BTreeMap::from([
+                ("emma".to_string(), posting()),
+                ("author:austen".to_string(), posting()),
+            ]),
+            metadata: BTreeMap::from([(
+                0,
+                Metadata {
+                    author: Some("austen".to_string()),
+                    ..Metadata::default()
+                },
+            )]),
+            json: BTreeSet::new(),
+        };
+        let mut bytes = Vec::new();
+        snapshot.write_to(&mut bytes).unwrap();
+        bytes[8..12].copy_from_slice(&3u32.to_be_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        let restored = Database::restore(DatabaseConfig::default(), &path).unwrap();
+        assert_eq!(restored.query("author:austen"), Ok(vec![0]));
+        assert_eq!(restored.query("emma"), Ok(vec![0]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_metadata_survives_restart_5() {
+        use ngram::metadata::Metadata;
+        let dir = data_dir("metadata");
+        let path = dir.join("archive.snapshot");
+        let metadata = Metadata {
+            title: Some("Hamlet".to_string()),
+            author: Some("William Shakespeare".to_string()),
+            year: Some(1599),
+            tags: vec!["play".to_string()],
+        };
+        {
+            let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+            database.publish("the ghost".to_string());
+            database
+                .publish_with_metadata("to be or not to be".to_string(), metadata.clone())
+                .unwrap();
+        }
+
+        let database = Database::open(DatabaseConfig::default(), &dir).unwrap();
+        assert_eq!(database.metadata(1), Some(metadata.clone()));
+        assert_eq!(database.metadata(0), Some(Metadata::default()));
+        assert_eq!(database.query("author:shakespeare"), Ok(vec![1]));
+        database.snapshot(&path).unwrap();
+
+        let restored = Database::restore(DatabaseConfig::default(), &path).unwrap();
+        assert_eq!(restored.metadata(1), Some(metadata));
+        assert_eq!(restored.query("tag:play"), Ok(vec![1]));
+        assert_eq!(
+            restored.fuzzy_search("shakespeare", 0.5),
+            Vec::<usize>::new()
+        );
+        drop(database);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ngram_counts_survive_restart_5() {
+        let dir = data_dir("ngram-counts");
+        let path = dir.join("archive.snapshot");
+        let config = DatabaseConfig {
+            max_ngram: 2,
+            ..Default::default()
+        };
+        let top = {
+            let database = Database::open(config.clone(), &dir).unwrap();
+            database.publish(fs::read_to_string("data/shakespeare-macbeth.txt").unwrap());
+            database.publish("the thane of cawdor lives".to_string());
+            database
+                .update(1, "the thane of glamis".to_string())
+                .unwrap();
+            database.publish("thane thane thane".to_string());
+            database.delete(2).unwrap();
+            database.top_ngrams(2, 20).unwrap()
+        };
+
+        let database = Database::open(config.clone(), &dir).unwrap();
+        assert_eq!(database.top_ngrams(2, 20).unwrap(), top);
+        database.snapshot(&path).unwrap();
+
+        let restored = Database::restore(config, &path).unwrap();
+        assert_eq!(restored.top_ngrams(2, 20).unwrap(), top);
+        assert_eq!(
+            restored.ngram_stats("thane of"),
+            database.ngram_stats("thane of")
+        );
+        drop(database);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_json_survives_restart_5() {
+        let dir = data_dir("json");
+        let path = dir.join("archive.snapshot");
+        let record = r#"{"title": "Emma", "tags": ["novel"]}"#;
+        let config = DatabaseConfig {
+            deduplicate: true,
+            ..Default::default()
+        };
+        {
+            let database = Database::open(config.clone(), &dir).unwrap();
+            database.publish(record.to_string());
+            assert_eq!(
+                database.publish_json(record.to_string()).unwrap(),
+                (1, false)
+            );
+            assert_eq!(
+                database.publish_json(record.to_string()).unwrap(),
+                (1, true)
+            );
+        }
+
+        let database = Database::open(config.clone(), &dir).unwrap();
+        assert_eq!(database.query("tags:novel"), Ok(vec![1]));
+        assert_eq!(database.retrieve(1).as_deref(), Some(record));
+        database.snapshot(&path).unwrap();
+
+        let restored = Database::restore(config, &path).unwrap();
+        assert_eq!(restored.query("title:emma"), Ok(vec![1]));
+        assert_eq!(restored.search("emma"), vec![0, 1]);
+        assert_eq!(
+            restored.publish_json(record.to_string()).unwrap(),
+            (1, true)
+        );
+        restored.delete(1).unwrap();
+        assert_eq!(restored.query("title:emma"), Ok(vec![]));
+        drop(database);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+// ============================ TOKENIZER ============================
+mod test_tokenizer {
+    use ngram::database::*;
+    use ngram::tokenizer::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_default_tokenizer_5() {
+        let text = "\"Hamlet,\" quoth HORATIO--o'er the Dane!";
+        let tokens = DefaultTokenizer.tokenize(text);
+        let words: Vec<&str> = tokens.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(
+            words,
+            vec!["hamlet", "quoth", "horatio", "oer", "the", "dane"]
+        );
+        for token in tokens.iter() {
+            assert_eq!(
+                text[token.start..token.end]
+                    .to_lowercase()
+                    .replace('\'', ""),
+                token.text
+            );
+        }
+    }
+
+    #[test]
+    fn test_stemming_tokenizer_5() {
+        let tokenizer = StemmingTokenizer::new(DefaultTokenizer);
+        let tokens = tokenizer.tokenize("Running, runs and run");
+        let words: Vec<&str> = tokens.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(words, vec!["run", "run", "and", "run"]);
+        assert_eq!((tokens[0].start, tokens[0].end), (0, 7));
+    }
+
+    #[test]
+    fn test_stemming_improves_recall_5() {
+        let plain = Database::default();
+        let stemmed = Database::new(DatabaseConfig {
+            tokenizer: Arc::new(StemmingTokenizer::new(DefaultTokenizer)),
+            ..DatabaseConfig::default()
+        });
+        for path in [
+            "data/austen-persuasion.txt",
+            "data/blake-poems.txt",
+            "data/carroll-alice.txt",
+            "data/shakespeare-caesar.txt",
+            "data/shakespeare-macbeth.txt",
+        ] {
+            let doc = std::fs::read_to_string(path).unwrap();
+            plain.publish(doc.clone());
+            stemmed.publish(doc);
+        }
+
+        let mut improved = 0;
+        for word in ["running", "loved", "kings", "walked", "happiness"] {
+            let plain_ids = plain.search(word);
+            let stemmed_ids = stemmed.search(word);
+            assert!(plain_ids.iter().all(|id| stemmed_ids.contains(id)));
+            if stemmed_ids.len() > plain_ids.len() {
+                improved += 1;
+            }
+        }
+        assert!(improved > 0);
+    }
+
+    #[test]
+    fn test_custom_tokenizer_5() {
+        let default = Database::default();
+        let whitespace = Database::new(DatabaseConfig {
+            tokenizer: Arc::new(WhitespaceTokenizer),
+            ..DatabaseConfig::default()
+        });
+        for database in [&default, &whitespace] {
+            database.publish("Alas, poor Yorick!".to_string());
+        }
+
+        assert_eq!(default.search("alas"), vec![0]);
+        assert_eq!(default.search("Yorick."), vec![0]);
+        assert_eq!(whitespace.search("alas"), Vec::<usize>::new());
+        assert_eq!(whitespace.search("ALAS,"), vec![0]);
+    }
+}
+
+// ============================ QUERY ============================
+mod test_query {
+    use ngram::database::*;
+    use ngram::query::*;
+
+    fn phrase(s: &str) -> Box<Query> {
+        Box::new(Query::Phrase(s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_precedence_5() {
+        assert_eq!(
+            Query::parse("hamlet AND ghost NOT horatio"),
+            Ok(Query::And(
+                Box::new(Query::And(phrase("hamlet"), phrase("ghost"))),
+                Box::new(Query::Not(phrase("horatio")))
+            ))
+        );
+        assert_eq!(
+            Query::parse("a OR b AND (c OR \"d e\")"),
+            Ok(Query::Or(
+                phrase("a"),
+                Box::new(Query::And(
+                    phrase("b"),
+                    Box::new(Query::Or(phrase("c"), phrase("d e")))
+                ))
+            ))
+        );
+        assert_eq!(
+            Query::parse("to be or not"),
+            Ok(Query::Phrase("to be or not".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_5() {
+        for query in [
+            "",
+            "(ghost",
+            "ghost)",
+            "AND ghost",
+            "ghost OR",
+            "\"ghost",
+            "()",
+        ] {
+            assert!(Query::parse(query).is_err(), "query {:?}", query);
+        }
+        assert_eq!(Query::parse("(ghost").unwrap_err().offset, 0);
+    }
+
+    #[test]
+    fn test_parse_fields_5() {
+        let field = |field: &str, phrase: &str| Query::Field {
+            field: field.to_string(),
+            phrase: phrase.to_string(),
+        };
+        assert_eq!(
+            Query::parse("author:austen ghost"),
+            Ok(Query::And(
+                Box::new(field("author", "austen")),
+                Box::new(Query::Phrase("ghost".to_string()))
+            ))
+        );
+        assert_eq!(
+            Query::parse("title:\"the cross\" OR NOT year:1816"),
+            Ok(Query::Or(
+                Box::new(field("title", "the cross")),
+                Box::new(Query::Not(Box::new(field("year", "1816"))))
+            ))
+        );
+        assert_eq!(
+            Query::parse("at 10:30"),
+            Ok(Query::Phrase("at 10:30".to_string()))
+        );
+        assert_eq!(
+            Query::parse("\"first-name\":jane OR \"full name\":\"jane austen\""),
+            Ok(Query::Or(
+                Box::new(field("first-name", "jane")),
+                Box::new(field("full name", "jane austen"))
+            ))
+        );
+        assert_eq!(
+            Query::parse("\"the end\" :x"),
+            Ok(Query::And(
+                Box::new(Query::Phrase("the end".to_string())),
+                Box::new(Query::Phrase(":x".to_string()))
+            ))
+        );
+        assert!(Query::parse("author:").is_err());
+        assert!(Query::parse("title:\"\"").is_err());
+        assert!(Query::parse("\"first-name\":").is_err());
+        assert!(Query::parse("\"first-name\":\"\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_patterns_5() {
+        assert_eq!(
+            Query::parse("lady mac* NOT *duff"),
+            Ok(Query::And(
+                Box::new(Query::And(
+                    Box::new(Query::Phrase("lady".to_string())),
+                    Box::new(Query::Wildcard("mac*".to_string()))
+                )),
+                Box::new(Query::Not(Box::new(Query::Wildcard("*duff".to_string()))))
+            ))
+        );
+        assert_eq!(
+            Query::parse("\"mac*\""),
+            Ok(Query::Phrase("mac*".to_string()))
+        );
+        assert!(Query::parse("**").is_err());
+    }
+
+    #[test]
+    fn test_wildcard_search_5() {
+        use ngram::terms::{wildcard_match, TermDictionary};
+        assert!(wildcard_match("mac*", "macbeth"));
+        assert!(wildcard_match("*beth", "macbeth"));
+        assert!(wildcard_match("m*c*th", "macbeth"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("*beth", "macduff"));
+        assert!(!wildcard_match("mac*d", "macbeth"));
+
+        let terms = TermDictionary::new();
+        for term in ["macbeth", "macduff", "mac", "elizabeth", "mad", "beth"] {
+            terms.insert(term);
+        }
+        terms.insert("mac");
+        assert_eq!(terms.len(), 6);
+        assert_eq!(
+            terms.expand("mac*", 10, |_| true),
+            vec!["mac", "macbeth", "macduff"]
+        );
+        assert_eq!(
+            terms.expand("*beth", 10, |_| true),
+            vec!["beth", "elizabeth", "macbeth"]
+        );
+        assert_eq!(
+            terms.expand("*a*", 10, |_| true),
+            vec!["elizabeth", "mac", "macbeth", "macduff", "mad"]
+        );
+        assert_eq!(terms.expand("mac*", 2, |_| true), vec!["mac", "macbeth"]);
+
+        let database = Database::new(DatabaseConfig {
+            max_expansions: 2,
+            ..Default::default()
+        });
+        let macbeth = database.publish("Macbeth and Banquo".to_string());
+        let macduff = database.publish("Enter Macduff".to_string());
+        let elizabeth = database.publish("Queen Elizabeth".to_string());
+        assert_eq!(database.query("MAC*"), Ok(vec![macbeth, macduff]));
+        assert_eq!(database.query("*beth"), Ok(vec![macbeth, elizabeth]));
+        assert_eq!(database.query("mac* NOT banquo"), Ok(vec![macduff]));
+        assert_eq!(database.query("*e*"), Ok(vec![macduff, elizabeth]));
+        database.delete(macduff).unwrap();
+        assert_eq!(database.query("macd*"), Ok(vec![]));
+
+        // Words left only in deleted or updated documents don't use up expansions
+        let macabre = database.publish("a macabre tale".to_string());
+        assert_eq!(database.query("mac*"), Ok(vec![macbeth, macabre]));
+        database
+            .update(macbeth, "Macbeth alone".to_string())
+            .unwrap();
+        database.publish("Enter Mackintosh".to_string());
+        database.update(macabre, "a grim tale".to_string()).unwrap();
+        assert_eq!(database.query("mac*"), Ok(vec![macbeth, 4]));
+        assert_eq!(
+            terms.expand("mac*", 10, |term| term != "mac"),
+            vec!["macbeth", "macduff"]
+        );
+    }
+
+    #[test]
+    fn test_parse_near_5() {
+        assert_eq!(
+            Query::parse("the king NEAR/5 crown OR queen"),
+            Ok(Query::Or(
+                Box::new(Query::Near {
+                    left: "the king".to_string(),
+                    right: "crown".to_string(),
+                    distance: 5
+                }),
+                Box::new(Query::Phrase("queen".to_string()))
+            ))
+        );
+        assert_eq!(
+            Query::parse("NOT king NEAR/0 \"the crown\""),
+            Ok(Query::Not(Box::new(Query::Near {
+                left: "king".to_string(),
+                right: "the crown".to_string(),
+                distance: 0
+            })))
+        );
+        assert!(Query::parse("king NEAR/x crown").is_err());
+        assert!(Query::parse("king NEAR/5").is_err());
+        assert!(Query::parse("(a OR b) NEAR/5 crown").is_err());
+        assert!(Query::parse("king NEAR/5 title:crown").is_err());
+        assert!(Query::parse("a NEAR/1 b NEAR/1 c").is_err());
+    }
+
+    #[test]
+    fn test_proximity_search_5() {
+        let database = Database::default();
+        let close = database.publish("the king wore a golden crown".to_string());
+        let far =
+            database.publish("the king said many things before the crown was lost".to_string());
+        let reversed = database.publish("a crown for the king".to_string());
+        database.publish("the king alone".to_string());
+
+        assert_eq!(
+            database.query("king NEAR/3 crown"),
+            Ok(vec![close, reversed])
+        );
+        assert_eq!(database.query("king NEAR/2 crown"), Ok(vec![reversed]));
+        assert_eq!(
+            database.query("king NEAR/10 crown"),
+            Ok(vec![close, far, reversed])
+        );
+        assert_eq!(database.query("king NEAR/0 \"golden crown\""), Ok(vec![]));
+        assert_eq!(database.query("wore NEAR/0 \"golden crown\""), Ok(vec![]));
+        assert_eq!(
+            database.query("wore NEAR/1 \"golden crown\""),
+            Ok(vec![close])
+        );
+        assert_eq!(
+            database.query("king NEAR/10 crown NOT lost"),
+            Ok(vec![close, reversed])
+        );
+
+        // The span counts the words from the first to the last word of the closest pair
+        assert_eq!(
+            database.query_spans("king NEAR/10 crown OR alone", 0, None),
+            Ok((
+                4,
+                vec![
+                    (close, Some(5)),
+                    (far, Some(7)),
+                    (reversed, Some(4)),
+                    (3, None)
+                ]
+            ))
+        );
+        assert_eq!(
+            database.query_spans("king NEAR/10 crown", 1, Some(1)),
+            Ok((3, vec![(far, Some(7))]))
+        );
+    }
+
+    #[test]
+    fn test_proximity_distinct_occurrences_5() {
+        let database = Database::default();
+        let once = database.publish("the king sat alone".to_string());
+        let twice = database.publish("the king and the king".to_string());
+        let shared = database.publish("the king sat down".to_string());
+
+        // A word is never near itself, only near another occurrence of it
+        assert_eq!(database.query("king NEAR/3 king"), Ok(vec![twice]));
+        assert_eq!(
+            database.query_spans("king NEAR/3 king", 0, None),
+            Ok((1, vec![(twice, Some(4))]))
+        );
+
+        // Overlapping phrases do not match through the words they share
+        assert_eq!(database.query("\"king sat\" NEAR/0 sat"), Ok(vec![]));
+        assert_eq!(
+            database.query("\"king sat\" NEAR/5 \"sat down\""),
+            Ok(vec![])
+        );
+        assert_eq!(database.query("\"king sat\" NEAR/0 down"), Ok(vec![shared]));
+        assert_eq!(database.query("sat NEAR/0 king"), Ok(vec![once, shared]));
+    }
+
+    #[test]
+    fn test_suggest_5() {
+        let database = Database::new(DatabaseConfig {
+            stop_words: ["the".to_string()].into_iter().collect(),
+            ..Default::default()
+        });
+        database.publish("the ghost haunts the castle".to_string());
+        database.publish("a host of ghosts".to_string());
+        database.publish("the ghost returns to the castle".to_string());
+        let gone = database.publish("gohst".to_string());
+        database.delete(gone).unwrap();
+
+        // Closer words come first, then more frequent ones
+        assert_eq!(
+            database.suggest("gohst", 5),
+            vec!["ghost", "ghosts", "host"]
+        );
+        assert_eq!(database.suggest("gohst", 1), vec!["ghost"]);
+        assert_eq!(database.suggest("casle AND hst", 5), vec!["castle", "host"]);
+        assert_eq!(
+            database.suggest("\"the gohst\" NOT hots", 5),
+            vec!["ghost", "ghosts", "host"]
+        );
+
+        // Only words that occur nowhere get suggestions
+        assert_eq!(database.suggest("ghost castle", 5), Vec::<String>::new());
+        assert_eq!(database.suggest("zzzzzz", 5), Vec::<String>::new());
+        assert_eq!(database.suggest("(gohst", 5), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_search_regex_5() {
+        let database = Database::new(DatabaseConfig {
+            max_expansions: 2,
+            ..Default::default()
+        });
+        let color = database.publish("the color red".to_string());
+        let colour = database.publish("the colour blue".to_string());
+        let colors = database.publish("many colors".to_string());
+
+        let matches = database.search_regex("colou?r").unwrap();
+        assert_eq!(matches.ids, vec![color, colour]);
+        assert_eq!((matches.terms, matches.truncated), (2, false));
+
+        // The expression must match whole words, ignoring case
+        let matches = database.search_regex("RED|blu").unwrap();
+        assert_eq!(matches.ids, vec![color]);
+
+        let matches = database.search_regex("colou?rs?").unwrap();
+        assert_eq!(matches.ids, vec![color, colors]);
+        assert_eq!((matches.terms, matches.truncated), (2, true));
+
+        // Words left only in deleted documents are neither counted nor looked up
+        database.delete(color).unwrap();
+        let matches = database.search_regex("colou?rs?").unwrap();
+        assert_eq!(matches.ids, vec![colour, colors]);
+        assert_eq!((matches.terms, matches.truncated), (2, false));
+
+        assert!(database.search_regex("colo(u").is_err());
+        assert!(matches!(
+            database.search_regex("red)|(blue"),
+            Err(QueryError::Regex(_))
+        ));
+        assert!(database.search_regex(&"a".repeat(1000)).is_err());
+        assert!(database.search_regex(r"(\w{100}){100}").is_err());
+        assert_eq!(
+            database.search_regex("x+").unwrap().ids,
+            Vec::<usize>::new()
+        );
+    }
+
+    #[test]
+    fn test_database_query_5() {
+        let database = Database::default();
+        let a = database.publish("hamlet sees the ghost".to_string());
+        let b = database.publish("hamlet and horatio see the ghost".to_string());
+        let c = database.publish("horatio alone".to_string());
+
+        assert_eq!(database.query("hamlet AND ghost NOT horatio"), Ok(vec![a]));
+        assert_eq!(database.query("hamlet OR horatio"), Ok(vec![a, b, c]));
+        assert_eq!(database.query("NOT hamlet"), Ok(vec![c]));
+        assert_eq!(database.query("(alone OR sees) the"), Ok(vec![a]));
+        assert_eq!(database.query("\"the ghost\" NOT \"see the\""), Ok(vec![a]));
+        assert!(database.query("hamlet AND").is_err());
+    }
+
+    #[test]
+    fn test_stop_words_5() {
+        let stop_words = std::fs::read_to_string("data/words.txt").unwrap();
+        let database = Database::new(DatabaseConfig {
+            stop_words: stop_words.split_whitespace().map(str::to_string).collect(),
+            ..DatabaseConfig::default()
+        });
+        let a = database.publish("The King of Denmark is dead".to_string());
+        let b = database.publish("a king in Denmark".to_string());
+
+        assert_eq!(
+            database.query("the"),
+            Err(QueryError::StopWord("the".to_string()))
+        );
+        assert!(matches!(
+            database.query("king NOT \"of the\""),
+            Err(QueryError::StopWord(_))
+        ));
+        assert_eq!(database.query("king"), Ok(vec![a, b]));
+        assert_eq!(database.query("\"the king of denmark\""), Ok(vec![a, b]));
+        assert_eq!(database.query("denmark is dead"), Ok(vec![a]));
+        assert_eq!(database.search("of"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_ranked_search_5() {
+        let database = Database::default();
+        let mut ids = Vec::new();
+        for path in [
+            "data/shakespeare-caesar.txt",
+            "data/shakespeare-hamlet.txt",
+            "data/shakespeare-macbeth.txt",
+        ] {
+            ids.push(database.publish(std::fs::read_to_string(path).unwrap()));
         }
-        quickcheck(passes_stress_test as fn(Vec<(i32, usize, bool)>));
+
+        let ranked = database.ranked_search("denmark OR ghost").unwrap();
+        assert_eq!(ranked.first().map(|(id, _)| *id), Some(ids[1]));
+        assert!(ranked.windows(2).all(|w| w[0].1 >= w[1].1));
+
+        let mut unranked: Vec<usize> = ranked.iter().map(|(id, _)| *id).collect();
+        unranked.sort();
+        assert_eq!(unranked, database.query("denmark OR ghost").unwrap());
     }
 }
 
@@ -103,6 +1913,7 @@ mod test_pool {
     use ngram::pool::*;
     use std::sync::{Arc, Mutex};
     #[test]
+    #[allow(clippy::empty_loop, clippy::assertions_on_constants)]
     fn test_uses_multiple_threads_5() {
         let pool = ThreadPool::new(4);
 
@@ -150,7 +1961,15 @@ mod test_serialize {
     fn test_round_trip_request_5() {
         fn round_trip_request(s: String, n: usize) {
             let pub_request = Request::Publish { doc: s.clone() };
-            let search_request = Request::Search { word: s };
+            let search_request = Request::Search {
+                word: s,
+                options: SearchOptions {
+                    snippets: n % 2 == 1,
+                    offset: n / 2,
+                    limit: n.is_multiple_of(3).then_some(n),
+                    spans: n.is_multiple_of(5),
+                },
+            };
             let retrieve_request = Request::Retrieve { id: n };
             assert_eq!(
                 Request::from_bytes(&pub_request.to_bytes()[..]).unwrap(),
@@ -168,10 +1987,25 @@ mod test_serialize {
         quickcheck(round_trip_request as fn(String, usize));
     }
 
+    #[test]
+    fn test_round_trip_fuzzy_request_5() {
+        fn round_trip_fuzzy_request(s: String, n: u8) {
+            let request = Request::FuzzySearch {
+                word: s,
+                threshold: n as f64 / u8::MAX as f64,
+            };
+            assert_eq!(
+                Request::from_bytes(&request.to_bytes()[..]).unwrap(),
+                request
+            );
+        }
+        quickcheck(round_trip_fuzzy_request as fn(String, u8));
+    }
+
     #[test]
     fn test_round_trip_response_5() {
         fn round_trip_response(s: String, n: usize) {
-            let pub_response = Response::PublishSuccess(n);
+            let pub_response = Response::PublishSuccess(n, n % 2 == 1);
             let search_response = Response::SearchSuccess(vec![n]);
             let retrieve_response = Response::RetrieveSuccess(s.clone());
             assert_eq!(
@@ -189,6 +2023,395 @@ mod test_serialize {
         }
         quickcheck(round_trip_response as fn(String, usize));
     }
+
+    #[test]
+    fn test_publish_success_flag_5() {
+        let mut bytes = Response::PublishSuccess(3, true).to_bytes();
+        assert_eq!(Response::from_bytes(&bytes[..bytes.len() - 1]), None);
+        *bytes.last_mut().unwrap() = 2;
+        assert_eq!(Response::from_bytes(&bytes[..]), None);
+    }
+
+    #[test]
+    fn test_round_trip_delete_5() {
+        fn round_trip_delete(n: usize) {
+            let request = Request::Delete { id: n };
+            assert_eq!(
+                Request::from_bytes(&request.to_bytes()[..]).unwrap(),
+                request
+            );
+            let response = Response::DeleteSuccess;
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_delete as fn(usize));
+    }
+
+    #[test]
+    fn test_round_trip_update_5() {
+        fn round_trip_update(s: String, n: usize) {
+            let request = Request::Update { id: n, doc: s };
+            assert_eq!(
+                Request::from_bytes(&request.to_bytes()[..]).unwrap(),
+                request
+            );
+            let response = Response::UpdateSuccess;
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_update as fn(String, usize));
+    }
+
+    #[test]
+    fn test_round_trip_snapshot_5() {
+        fn round_trip_snapshot(s: String) {
+            let request = Request::Snapshot { path: s };
+            assert_eq!(
+                Request::from_bytes(&request.to_bytes()[..]).unwrap(),
+                request
+            );
+            let response = Response::SnapshotSuccess;
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_snapshot as fn(String));
+    }
+
+    #[test]
+    fn test_huge_snapshot_path_length_5() {
+        let mut bytes = vec![0x08];
+        bytes.extend(usize::MAX.to_be_bytes());
+        assert_eq!(Request::from_bytes(&bytes[..]), None);
+    }
+
+    #[test]
+    fn test_overflowing_postings_5() {
+        use ngram::snapshot::{read_postings, write_varint};
+        use std::io::ErrorKind;
+
+        let mut doc_overflow = Vec::new();
+        for value in [2, u64::MAX, 0, 1, 0] {
+            write_varint(&mut doc_overflow, value).unwrap();
+        }
+        let error = read_postings(&mut &doc_overflow[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let mut position_overflow = Vec::new();
+        for value in [1, 0, 2, u64::MAX, 1] {
+            write_varint(&mut position_overflow, value).unwrap();
+        }
+        let error = read_postings(&mut &position_overflow[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_round_trip_index_stats_5() {
+        use ngram::index::IndexStats;
+        use ngram::posting::PostingStats;
+        fn round_trip_index_stats(memory: (usize, usize, usize, usize), disk: (usize, u64)) {
+            let request = Request::IndexStats;
+            assert_eq!(
+                Request::from_bytes(&request.to_bytes()[..]).unwrap(),
+                request
+            );
+            let response = Response::IndexStatsSuccess(IndexStats {
+                memory: PostingStats {
+                    keys: memory.0,
+                    postings: memory.1,
+                    compressed_bytes: memory.2,
+                    uncompressed_bytes: memory.3,
+                },
+                segments: disk.0,
+                segment_bytes: disk.1,
+            });
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_index_stats as fn((usize, usize, usize, usize), (usize, u64)));
+    }
+
+    #[test]
+    fn test_round_trip_metadata_5() {
+        use ngram::metadata::Metadata;
+        fn round_trip_metadata(
+            doc: String,
+            fields: (Option<String>, Option<String>, Option<u32>),
+            tags: Vec<String>,
+            id: usize,
+        ) {
+            let metadata = Metadata {
+                title: fields.0,
+                author: fields.1,
+                year: fields.2,
+                tags,
+            };
+            let requests = [
+                Request::PublishWithMetadata {
+                    doc,
+                    metadata: metadata.clone(),
+                },
+                Request::RetrieveMetadata { id },
+            ];
+            for request in requests {
+                assert_eq!(
+                    Request::from_bytes(&request.to_bytes()[..]).unwrap(),
+                    request
+                );
+            }
+            let response = Response::MetadataSuccess(metadata);
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(
+            round_trip_metadata
+                as fn(String, (Option<String>, Option<String>, Option<u32>), Vec<String>, usize),
+        );
+    }
+
+    #[test]
+    fn test_round_trip_ranked_search_5() {
+        fn round_trip_ranked_search(s: String, results: Vec<(usize, u32)>) {
+            let request = Request::RankedSearch { query: s };
+            let response = Response::RankedSearchSuccess(
+                results
+                    .into_iter()
+                    .map(|(id, score)| (id, score as f64 / 7.0))
+                    .collect(),
+            );
+            assert_eq!(
+                Request::from_bytes(&request.to_bytes()[..]).unwrap(),
+                request
+            );
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_ranked_search as fn(String, Vec<(usize, u32)>));
+    }
+
+    #[test]
+    fn test_round_trip_invalid_query_5() {
+        fn round_trip_invalid_query(s: String) {
+            let response = Response::InvalidQuery(s.clone());
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+            let response = Response::StopWord(s);
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_invalid_query as fn(String));
+    }
+
+    #[test]
+    fn test_round_trip_search_hits_5() {
+        use ngram::snippet::*;
+        // A document id, a snippet and the ends of each match
+        type Hit = (usize, String, Vec<(u8, u8)>);
+        fn round_trip_search_hits(total: usize, hits: Vec<Hit>) {
+            let hits = hits
+                .into_iter()
+                .map(|(id, text, matches)| {
+                    // Only ranges on character boundaries of the text are valid
+                    let matches = matches
+                        .into_iter()
+                        .map(|(a, b)| (a.min(b) as usize, a.max(b) as usize))
+                        .filter(|&(a, b)| text.is_char_boundary(a) && text.is_char_boundary(b))
+                        .collect();
+                    SearchHit {
+                        id,
+                        snippet: Snippet { text, matches },
+                    }
+                })
+                .collect();
+            let response = Response::SearchHits { total, hits };
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_search_hits as fn(usize, Vec<Hit>));
+    }
+
+    #[test]
+    fn test_round_trip_search_page_5() {
+        fn round_trip_search_page(total: usize, ids: Vec<usize>) {
+            let response = Response::SearchPage { total, ids };
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_search_page as fn(usize, Vec<usize>));
+    }
+
+    #[test]
+    fn test_truncated_search_5() {
+        fn truncated_search(word: String, limit: Option<usize>) {
+            let request = Request::Search {
+                word,
+                options: SearchOptions {
+                    limit,
+                    ..Default::default()
+                },
+            };
+            let bytes = request.to_bytes();
+            for len in 0..bytes.len() {
+                assert_eq!(Request::from_bytes(&bytes[..len]), None);
+            }
+        }
+        quickcheck(truncated_search as fn(String, Option<usize>));
+    }
+
+    #[test]
+    fn test_huge_metadata_document_length_5() {
+        let mut bytes = vec![0x0A];
+        bytes.extend(usize::MAX.to_be_bytes());
+        bytes.extend([0, 0, 0]);
+        assert_eq!(Request::from_bytes(&bytes[..]), None);
+    }
+
+    #[test]
+    fn test_huge_ranked_search_length_5() {
+        let mut bytes = vec![0x05];
+        bytes.extend(usize::MAX.to_be_bytes());
+        assert_eq!(Request::from_bytes(&bytes[..]), None);
+
+        let mut bytes = vec![0x06];
+        bytes.extend(usize::MAX.to_be_bytes());
+        assert_eq!(Response::from_bytes(&bytes[..]), None);
+    }
+
+    #[test]
+    fn test_huge_fuzzy_length_5() {
+        let mut bytes = vec![0x04];
+        bytes.extend(usize::MAX.to_be_bytes());
+        bytes.extend(0.5f64.to_be_bytes());
+        assert_eq!(Request::from_bytes(&bytes[..]), None);
+    }
+
+    #[test]
+    fn test_huge_update_length_5() {
+        let mut bytes = vec![0x07];
+        bytes.extend(0usize.to_be_bytes());
+        bytes.extend((1usize << 62).to_be_bytes());
+        bytes.extend(b"short");
+        assert_eq!(Request::from_bytes(&bytes[..]), None);
+    }
+
+    #[test]
+    fn test_round_trip_search_spans_5() {
+        fn round_trip_search_spans(total: usize, spans: Vec<(usize, Option<usize>)>) {
+            let response = Response::SearchSpans { total, spans };
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_search_spans as fn(usize, Vec<(usize, Option<usize>)>));
+    }
+
+    #[test]
+    fn test_round_trip_suggestions_5() {
+        fn round_trip_suggestions(suggestions: Vec<String>) {
+            let response = Response::SearchSuggestions(suggestions);
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_suggestions as fn(Vec<String>));
+    }
+
+    #[test]
+    fn test_round_trip_regex_5() {
+        fn round_trip_regex(pattern: String, ids: Vec<usize>, terms: usize, truncated: bool) {
+            let request = Request::SearchRegex { pattern };
+            assert_eq!(
+                Request::from_bytes(&request.to_bytes()[..]).unwrap(),
+                request
+            );
+            let response = Response::RegexSearchSuccess {
+                ids,
+                terms,
+                truncated,
+            };
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_regex as fn(String, Vec<usize>, usize, bool));
+    }
+
+    #[test]
+    fn test_round_trip_ngrams_5() {
+        use ngram::counts::NgramStats;
+        fn round_trip_ngrams(ngram: String, n: usize, counts: Vec<(usize, usize)>) {
+            for request in [
+                Request::NgramStats {
+                    ngram: ngram.clone(),
+                },
+                Request::TopNgrams { n, k: n / 2 },
+            ] {
+                assert_eq!(
+                    Request::from_bytes(&request.to_bytes()[..]).unwrap(),
+                    request
+                );
+            }
+            let response = Response::NgramStatsSuccess(NgramStats {
+                total: counts.iter().map(|(_, count)| count % 1000).sum(),
+                documents: counts.len(),
+                counts: counts.clone(),
+            });
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+            let response = Response::TopNgramsSuccess(
+                counts
+                    .iter()
+                    .map(|&(_, count)| (ngram.clone(), count))
+                    .collect(),
+            );
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_ngrams as fn(String, usize, Vec<(usize, usize)>));
+    }
+
+    #[test]
+    fn test_round_trip_json_5() {
+        fn round_trip_json(s: String) {
+            let request = Request::PublishJson { doc: s.clone() };
+            assert_eq!(
+                Request::from_bytes(&request.to_bytes()[..]).unwrap(),
+                request
+            );
+            let response = Response::InvalidDocument(s);
+            assert_eq!(
+                Response::from_bytes(&response.to_bytes()[..]).unwrap(),
+                response
+            );
+        }
+        quickcheck(round_trip_json as fn(String));
+    }
 }
 
 // ============================ ARGUMENTS ============================
@@ -207,7 +2430,11 @@ mod integration {
     use std::time::Duration;
 
     fn start_server(port: u16) -> (Arc<server::Server>, JoinHandle<()>) {
-        let server = Arc::new(server::Server::new());
+        run_server(server::Server::new(), port)
+    }
+
+    fn run_server(server: server::Server, port: u16) -> (Arc<server::Server>, JoinHandle<()>) {
+        let server = Arc::new(server);
         let handle = thread::spawn({
             let server = Arc::clone(&server);
             move || server.run(port)
@@ -230,7 +2457,7 @@ mod integration {
 
         let client = client::Client::new("127.0.0.1", port);
         let response = client.publish_from_path("data/austen-emma.txt");
-        assert!(matches!(response, Some(Response::PublishSuccess(_))));
+        assert!(matches!(response, Some(Response::PublishSuccess(..))));
         server.stop();
     }
 
@@ -254,7 +2481,7 @@ mod integration {
 
         let response = client.publish_from_path("data/austen-emma.txt");
         let id = match response {
-            Some(Response::PublishSuccess(id)) => id,
+            Some(Response::PublishSuccess(id, _)) => id,
             _ => panic!("Failed to publish data/austen-emma.txt"),
         };
         let response = client.search("the");
@@ -269,11 +2496,11 @@ mod integration {
 
         let client = client::Client::new("127.0.0.1", port);
         let id1 = match client.publish_from_path("data/austen-emma.txt") {
-            Some(Response::PublishSuccess(id)) => id,
+            Some(Response::PublishSuccess(id, _)) => id,
             _ => panic!("Failed to publish data/austen-emma.txt"),
         };
         let id2 = match client.publish_from_path("data/austen-persuasion.txt") {
-            Some(Response::PublishSuccess(id)) => id,
+            Some(Response::PublishSuccess(id, _)) => id,
             _ => panic!("Failed to publish data/austen-persuasion.txt"),
         };
 
@@ -295,11 +2522,11 @@ mod integration {
 
         let client = client::Client::new("127.0.0.1", port);
         let _id1 = match client.publish_from_path("data/austen-persuasion.txt") {
-            Some(Response::PublishSuccess(id)) => id,
+            Some(Response::PublishSuccess(id, _)) => id,
             _ => panic!("Failed to publish data/austen-persuasion.txt"),
         };
         let id2 = match client.publish_from_path("data/austen-emma.txt") {
-            Some(Response::PublishSuccess(id)) => id,
+            Some(Response::PublishSuccess(id, _)) => id,
             _ => panic!("Failed to publish data/austen-emma.txt"),
         };
 
@@ -315,7 +2542,7 @@ mod integration {
 
         let client = client::Client::new("127.0.0.1", port);
         let id = match client.publish_from_path("data/austen-emma.txt") {
-            Some(Response::PublishSuccess(id)) => id,
+            Some(Response::PublishSuccess(id, _)) => id,
             _ => panic!("Failed to publish data/austen-emma.txt"),
         };
         let doc = std::fs::read_to_string("data/austen-emma.txt").unwrap();
@@ -325,6 +2552,217 @@ mod integration {
     }
 
     #[test]
+    fn test_search_invalid_query_5() {
+        let port = 7887;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let response = client.search("(emma OR");
+        assert!(matches!(response, Some(Response::InvalidQuery(_))));
+        server.stop();
+    }
+
+    #[test]
+    fn test_snapshot_5() {
+        let port = 7888;
+        let dir = std::env::temp_dir().join(format!("ngram-snapshots-{}", port));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let (server, _handle) =
+            run_server(server::Server::new().with_snapshot_dir(dir.clone()), port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let id = match client.publish_from_path("data/blake-poems.txt") {
+            Some(Response::PublishSuccess(id, _)) => id,
+            _ => panic!("Failed to publish data/blake-poems.txt"),
+        };
+        let response = client.snapshot("archive.snapshot");
+        assert_eq!(response, Some(Response::SnapshotSuccess));
+
+        // Paths that could leave the snapshot directory are refused
+        let outside = std::env::temp_dir().join(format!("ngram-outside-{}.snapshot", port));
+        let response = client.snapshot(outside.to_str().unwrap());
+        assert_eq!(response, Some(Response::Failure));
+        assert!(!outside.exists());
+        let response = client.snapshot(&format!("../ngram-outside-{}.snapshot", port));
+        assert_eq!(response, Some(Response::Failure));
+        assert!(!outside.exists());
+        server.stop();
+
+        // Only the snapshot is left in the directory, without any temporary file
+        let entries: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec!["archive.snapshot"]);
+        let path = dir.join("archive.snapshot");
+        let restored = ngram::database::Database::restore(Default::default(), &path).unwrap();
+        let doc = fs::read_to_string("data/blake-poems.txt").unwrap();
+        assert_eq!(restored.retrieve(id), Some(doc));
+        assert_eq!(restored.search("lamb"), vec![id]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_disabled_5() {
+        let port = 7895;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let response = client.snapshot("archive.snapshot");
+        assert_eq!(response, Some(Response::Failure));
+        server.stop();
+    }
+
+    #[test]
+    fn test_publish_json_5() {
+        let port = 7890;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let dir = std::env::temp_dir();
+        let record = dir.join(format!("ngram-record-{}.json", port));
+        let invalid = dir.join(format!("ngram-invalid-{}.json", port));
+        fs::write(
+            &record,
+            r#"{"author": "Lewis Carroll", "text": "down the rabbit hole"}"#,
+        )
+        .unwrap();
+        fs::write(&invalid, "down the rabbit hole").unwrap();
+
+        let id = match client.publish_json_from_path(record.to_str().unwrap()) {
+            Some(Response::PublishSuccess(id, false)) => id,
+            response => panic!("Failed to publish JSON: {:?}", response),
+        };
+        let response = client.search("author:carroll AND text:rabbit");
+        assert_eq!(response, Some(Response::SearchSuccess(vec![id])));
+        let response = client.publish_json_from_path(invalid.to_str().unwrap());
+        assert!(matches!(response, Some(Response::InvalidDocument(_))));
+        server.stop();
+
+        fs::remove_file(&record).unwrap();
+        fs::remove_file(&invalid).unwrap();
+    }
+
+    #[test]
+    fn test_search_with_snippets_5() {
+        let port = 7891;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let id = match client.publish_from_path("data/carroll-alice.txt") {
+            Some(Response::PublishSuccess(id, _)) => id,
+            _ => panic!("Failed to publish data/carroll-alice.txt"),
+        };
+        let options = SearchOptions {
+            snippets: true,
+            ..Default::default()
+        };
+        let hits = match client.search_with_options("rabbit", options) {
+            Some(Response::SearchHits { total: 1, hits }) => hits,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].id, id);
+        let (start, end) = hits[0].snippet.matches[0];
+        assert_eq!(hits[0].snippet.text[start..end].to_lowercase(), "rabbit");
+        server.stop();
+    }
+
+    #[test]
+    fn test_search_page_5() {
+        let port = 7892;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        for path in [
+            "data/austen-emma.txt",
+            "data/austen-persuasion.txt",
+            "data/austen-sense.txt",
+        ] {
+            client.publish_from_path(path);
+        }
+        let options = SearchOptions {
+            offset: 1,
+            limit: Some(1),
+            ..Default::default()
+        };
+        let response = client.search_with_options("austen", options);
+        assert_eq!(
+            response,
+            Some(Response::SearchPage {
+                total: 3,
+                ids: vec![1]
+            })
+        );
+        let response = client.search("austen");
+        assert_eq!(response, Some(Response::SearchSuccess(vec![0, 1, 2])));
+        server.stop();
+    }
+
+    #[test]
+    fn test_search_suggestions_5() {
+        let port = 7893;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        client.publish_from_path("data/shakespeare-macbeth.txt");
+        let response = client.search("macbteh");
+        assert!(
+            matches!(response, Some(Response::SearchSuggestions(ref suggestions)) if suggestions[0] == "macbeth"),
+            "{:?}",
+            response
+        );
+        let options = SearchOptions {
+            limit: Some(10),
+            ..Default::default()
+        };
+        let response = client.search_with_options("xyzzy", options);
+        assert_eq!(
+            response,
+            Some(Response::SearchPage {
+                total: 0,
+                ids: vec![]
+            })
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_ngram_stats_5() {
+        let port = 7894;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        client.publish_from_path("data/shakespeare-macbeth.txt");
+        client.publish_from_path("data/shakespeare-hamlet.txt");
+        match client.ngram_stats("macbeth") {
+            Some(Response::NgramStatsSuccess(stats)) => {
+                assert_eq!(stats.documents, stats.counts.len());
+                assert_eq!(stats.counts[0].0, 0);
+                assert_eq!(
+                    stats.total,
+                    stats.counts.iter().map(|(_, count)| count).sum::<usize>()
+                );
+            }
+            response => panic!("{:?}", response),
+        }
+        match client.top_ngrams(1, 5) {
+            Some(Response::TopNgramsSuccess(ngrams)) => {
+                assert_eq!(ngrams.len(), 5);
+                assert!(ngrams.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+            }
+            response => panic!("{:?}", response),
+        }
+        match client.top_ngrams(2, 5) {
+            Some(Response::InvalidQuery(reason)) => assert!(reason.contains("--max-ngram")),
+            response => panic!("{:?}", response),
+        }
+        server.stop();
+    }
+
+    #[test]
+    #[allow(unused_variables, clippy::clone_on_copy)]
     fn test_server_stress_test_10() {
         let port = 7889;
         let (server, _handle) = start_server(port);
@@ -384,7 +2822,10 @@ mod integration {
         let client = client::Client::new("127.0.0.1", port);
         for word in words.iter() {
             let response = client.search(word);
-            assert!(matches!(response, Some(Response::SearchSuccess(_))));
+            assert!(matches!(
+                response,
+                Some(Response::SearchSuccess(_) | Response::SearchSuggestions(_))
+            ));
         }
         // println!("Sequential search took {:?}", _now.elapsed);
 
@@ -401,7 +2842,12 @@ mod integration {
                         match word {
                             Some(word) => {
                                 let response = client.search(&word);
-                                assert!(matches!(response, Some(Response::SearchSuccess(_))));
+                                assert!(matches!(
+                                    response,
+                                    Some(
+                                        Response::SearchSuccess(_) | Response::SearchSuggestions(_)
+                                    )
+                                ));
                                 //println!("Found {} in {:?}", word, indices);
                             }
                             None => return,